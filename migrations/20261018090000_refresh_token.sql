@@ -0,0 +1,15 @@
+-- Refresh tokens are rotated on every use; all tokens issued from one login share a family_id
+CREATE TABLE refresh_token (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    family_id UUID NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    FOREIGN KEY (user_id) REFERENCES "User"(id) ON DELETE CASCADE
+);
+
+CREATE INDEX refresh_token_family_index ON refresh_token(family_id);
+CREATE INDEX refresh_token_user_index ON refresh_token(user_id);
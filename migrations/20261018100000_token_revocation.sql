@@ -0,0 +1,15 @@
+-- Access tokens revoked before their exp (logout)
+CREATE TABLE revoked_token (
+    jti UUID PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Every access token of a user issued at or before revoked_before is rejected
+-- (user deletion, admin force-logout)
+CREATE TABLE user_revocation (
+    username VARCHAR(10) PRIMARY KEY,
+    revoked_before TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX revoked_token_expires_index ON revoked_token(expires_at);
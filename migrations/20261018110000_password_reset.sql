@@ -0,0 +1,12 @@
+-- Single-use password reset tokens, only the SHA-256 of the token is stored
+CREATE TABLE password_reset_token (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    used_at TIMESTAMPTZ,
+    FOREIGN KEY (user_id) REFERENCES "User"(id) ON DELETE CASCADE
+);
+
+CREATE INDEX password_reset_token_user_index ON password_reset_token(user_id);
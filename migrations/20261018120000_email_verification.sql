@@ -0,0 +1,27 @@
+ALTER TABLE "User"
+ADD COLUMN email_verified_at TIMESTAMPTZ;
+
+-- Tokens mailed to confirm an email address, only the SHA-256 of the token is stored
+CREATE TABLE email_verification_token (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    FOREIGN KEY (user_id) REFERENCES "User"(id) ON DELETE CASCADE
+);
+
+-- Products bought by a user
+CREATE TABLE purchase (
+    id BIGSERIAL PRIMARY KEY,
+    product_id BIGINT NOT NULL,
+    buyer_id INTEGER NOT NULL,
+    price INTEGER NOT NULL CHECK (price >= 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (product_id, buyer_id),
+    FOREIGN KEY (product_id) REFERENCES Product(id) ON DELETE CASCADE,
+    FOREIGN KEY (buyer_id) REFERENCES "User"(id) ON DELETE CASCADE
+);
+
+CREATE INDEX email_verification_token_user_index ON email_verification_token(user_id);
+CREATE INDEX purchase_buyer_index ON purchase(buyer_id);
@@ -0,0 +1,26 @@
+-- TOTP secret of a user, enrollment is pending until confirmed_at is set
+CREATE TABLE user_mfa (
+    user_id INTEGER PRIMARY KEY,
+    secret VARCHAR(64) NOT NULL,
+    confirmed_at TIMESTAMPTZ,
+    last_step BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    FOREIGN KEY (user_id) REFERENCES "User"(id) ON DELETE CASCADE
+);
+
+-- Single-use recovery codes, only the SHA-256 of a code is stored
+CREATE TABLE mfa_recovery_code (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    code_hash VARCHAR(64) NOT NULL,
+    used_at TIMESTAMPTZ,
+    FOREIGN KEY (user_id) REFERENCES "User"(id) ON DELETE CASCADE
+);
+
+-- Roles whose accounts can't sign in without two-factor authentication
+CREATE TABLE role_mfa_policy (
+    role Roles PRIMARY KEY,
+    required BOOLEAN NOT NULL DEFAULT false
+);
+
+CREATE INDEX mfa_recovery_code_user_index ON mfa_recovery_code(user_id);
@@ -0,0 +1,7 @@
+-- Failed login attempts per account ("user:<username>") and per client ("ip:<address>")
+CREATE TABLE login_throttle (
+    key VARCHAR(128) PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    locked_until TIMESTAMPTZ
+);
@@ -0,0 +1,2 @@
+-- Moderators look after listings and lockouts but can't manage accounts or keys
+ALTER TYPE Roles ADD VALUE 'moderator';
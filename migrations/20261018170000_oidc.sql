@@ -0,0 +1,26 @@
+-- Pending authorization-code logins, one row per redirect to an identity provider
+CREATE TABLE oidc_login_state (
+    state_hash VARCHAR(64) PRIMARY KEY,
+    provider VARCHAR(32) NOT NULL,
+    code_verifier VARCHAR(128) NOT NULL,
+    -- set when a signed-in user links a provider instead of logging in
+    link_user_id INTEGER,
+    expires_at TIMESTAMPTZ NOT NULL,
+    FOREIGN KEY (link_user_id) REFERENCES "User"(id) ON DELETE CASCADE
+);
+
+-- External subjects that may sign in as a user, at most one per provider
+CREATE TABLE user_identity (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    provider VARCHAR(32) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    email VARCHAR(255),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_login_at TIMESTAMPTZ,
+    UNIQUE (provider, subject),
+    UNIQUE (user_id, provider),
+    FOREIGN KEY (user_id) REFERENCES "User"(id) ON DELETE CASCADE
+);
+
+CREATE INDEX user_identity_user_index ON user_identity(user_id);
@@ -0,0 +1,13 @@
+-- One row per login; the id is the family_id of the refresh tokens and the sid claim of access tokens
+CREATE TABLE session (
+    id UUID PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    user_agent TEXT,
+    ip VARCHAR(45),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMPTZ,
+    FOREIGN KEY (user_id) REFERENCES "User"(id) ON DELETE CASCADE
+);
+
+CREATE INDEX session_user_index ON session(user_id);
@@ -0,0 +1,16 @@
+-- Every impersonation token an admin minted and every request made with one
+CREATE TABLE impersonation_audit (
+    id BIGSERIAL PRIMARY KEY,
+    impersonator VARCHAR(10) NOT NULL,
+    target VARCHAR(10) NOT NULL,
+    jti UUID NOT NULL,
+    -- 'issued' or 'request'
+    event VARCHAR(16) NOT NULL,
+    method VARCHAR(16),
+    path TEXT,
+    status SMALLINT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX impersonation_audit_target_index ON impersonation_audit(target);
+CREATE INDEX impersonation_audit_jti_index ON impersonation_audit(jti);
@@ -0,0 +1,28 @@
+-- Who changed what; rows are never updated or deleted
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    actor VARCHAR(10),
+    impersonator VARCHAR(10),
+    action VARCHAR(32) NOT NULL,
+    target_type VARCHAR(16) NOT NULL,
+    target_id VARCHAR(64) NOT NULL,
+    -- {"field": {"before": .., "after": ..}} for every field that changed
+    diff JSONB NOT NULL,
+    ip VARCHAR(45),
+    request_id VARCHAR(64),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX audit_log_actor_index ON audit_log(actor);
+CREATE INDEX audit_log_target_index ON audit_log(target_type, target_id);
+CREATE INDEX audit_log_created_at_index ON audit_log(created_at);
+
+CREATE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_append_only
+    BEFORE UPDATE OR DELETE OR TRUNCATE ON audit_log
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only();
@@ -0,0 +1,16 @@
+-- Deleted accounts are kept for a restore window before the purge job removes them
+ALTER TABLE "User"
+ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX user_deleted_at_index ON "User"(deleted_at) WHERE deleted_at IS NOT NULL;
+
+-- Unlisted products stay reachable for their buyers but can't be found or bought
+ALTER TABLE Product
+ADD COLUMN unlisted_at TIMESTAMPTZ;
+
+-- Purging a seller must not take the products buyers paid for with it
+ALTER TABLE Product
+ALTER COLUMN owner_id DROP NOT NULL,
+DROP CONSTRAINT product_owner_id_fkey,
+ADD CONSTRAINT product_owner_id_fkey
+    FOREIGN KEY (owner_id) REFERENCES "User"(id) ON DELETE SET NULL;
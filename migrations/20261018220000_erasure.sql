@@ -0,0 +1,4 @@
+-- Erased accounts keep their row (and id) so purchases and products stay consistent,
+-- but everything identifying is overwritten and the purge job leaves them alone
+ALTER TABLE "User"
+ADD COLUMN erased_at TIMESTAMPTZ;
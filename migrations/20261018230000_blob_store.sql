@@ -0,0 +1,34 @@
+-- Executables live in the blob store under their SHA-256, rows only keep a reference
+CREATE TABLE blob (
+    sha256 CHAR(64) PRIMARY KEY,
+    size BIGINT NOT NULL CHECK (size >= 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Content that was still in Product.executable, the server copies it to the blob store
+-- at startup and empties this table
+CREATE TABLE legacy_blob (
+    sha256 CHAR(64) PRIMARY KEY REFERENCES blob(sha256) ON DELETE CASCADE,
+    data BYTEA NOT NULL
+);
+
+ALTER TABLE Product
+ADD COLUMN executable_sha256 CHAR(64) REFERENCES blob(sha256),
+ADD COLUMN executable_size BIGINT;
+
+INSERT INTO blob (sha256, size)
+SELECT DISTINCT encode(sha256(executable), 'hex'), octet_length(executable)
+FROM Product WHERE executable IS NOT NULL;
+
+INSERT INTO legacy_blob (sha256, data)
+SELECT DISTINCT ON (1) encode(sha256(executable), 'hex'), executable
+FROM Product WHERE executable IS NOT NULL;
+
+UPDATE Product
+SET executable_sha256 = encode(sha256(executable), 'hex'),
+    executable_size = octet_length(executable)
+WHERE executable IS NOT NULL;
+
+ALTER TABLE Product DROP COLUMN executable;
+
+CREATE INDEX product_executable_index ON Product(executable_sha256);
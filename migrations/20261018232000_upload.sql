@@ -0,0 +1,13 @@
+-- Resumable artifact uploads, the chunks received so far are staged on disk
+CREATE TABLE upload (
+    id UUID PRIMARY KEY,
+    product_id BIGINT NOT NULL REFERENCES Product(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES "User"(id) ON DELETE CASCADE,
+    size BIGINT NOT NULL CHECK (size > 0),
+    received BIGINT NOT NULL DEFAULT 0 CHECK (received >= 0 AND received <= size),
+    sha256 CHAR(64),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX upload_expires_index ON upload(expires_at);
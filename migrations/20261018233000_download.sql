@@ -0,0 +1,11 @@
+-- One row per download of a product, anonymous ones have no user
+CREATE TABLE download (
+    id BIGSERIAL PRIMARY KEY,
+    product_id BIGINT NOT NULL REFERENCES Product(id) ON DELETE CASCADE,
+    user_id INTEGER REFERENCES "User"(id) ON DELETE SET NULL,
+    sha256 CHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX download_product_index ON download(product_id);
+CREATE INDEX download_user_index ON download(user_id);
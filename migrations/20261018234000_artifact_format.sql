@@ -0,0 +1,5 @@
+-- What inspecting a blob found, NULL until it is inspected
+ALTER TABLE blob ADD COLUMN inspection JSONB;
+
+-- Platforms a seller declared for a resumable upload, checked when it completes
+ALTER TABLE upload ADD COLUMN platforms TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,17 @@
+-- Per-platform builds of a product, next to the default executable on Product
+CREATE TABLE artifact (
+    id BIGSERIAL PRIMARY KEY,
+    product_id BIGINT NOT NULL REFERENCES Product(id) ON DELETE CASCADE,
+    os VARCHAR(16) NOT NULL,
+    -- NULL runs on every architecture of the OS, like a universal Mach-O
+    arch VARCHAR(16),
+    variant VARCHAR(32),
+    sha256 CHAR(64) NOT NULL REFERENCES blob(sha256),
+    size BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE NULLS NOT DISTINCT (product_id, os, arch, variant)
+);
+
+CREATE INDEX artifact_sha256_index ON artifact(sha256);
+
+ALTER TABLE upload ADD COLUMN variant VARCHAR(32);
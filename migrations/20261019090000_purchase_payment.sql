@@ -0,0 +1,4 @@
+-- A purchase only grants access once the payment provider confirmed it
+ALTER TABLE purchase
+ADD COLUMN payment_ref VARCHAR(128) UNIQUE,
+ADD COLUMN paid_at TIMESTAMPTZ;
@@ -0,0 +1,5 @@
+-- Ties a pending login to the browser that started it through an HttpOnly cookie,
+-- logins started before this can't be finished
+DELETE FROM oidc_login_state;
+ALTER TABLE oidc_login_state
+ADD COLUMN nonce_hash VARCHAR(64) NOT NULL;
@@ -0,0 +1,10 @@
+-- Erasing an account pseudonymizes its audit entries, only a transaction that set
+-- devmarket.audit_erasure may update rows; deleting them stays impossible
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND current_setting('devmarket.audit_erasure', true) = 'on' THEN
+        RETURN NULL;
+    END IF;
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
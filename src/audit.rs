@@ -0,0 +1,223 @@
+use crate::State;
+use crate::error::Result;
+use crate::user::Clains;
+use crate::user::model::User;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use sqlx::query_as;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tracing::warn;
+
+/// Binary content isn't worth diffing.
+const IGNORED_FIELDS: [&str; 1] = ["executable"];
+
+/// Who is making the request and where it comes from.
+#[derive(Debug, Default, Clone)]
+pub struct Audit {
+    pub actor: Option<String>,
+    pub impersonator: Option<String>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+}
+impl Audit {
+    pub fn by(mut self, claims: &Clains) -> Self {
+        self.actor = Some(claims.username.clone());
+        self.impersonator = claims.act.as_ref().map(|actor| actor.sub.clone());
+        self
+    }
+    pub fn by_user(mut self, user: &User) -> Self {
+        self.actor = Some(user.username.clone());
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Audit {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        Ok(Self {
+            ip: parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string()),
+            request_id: parts
+                .headers
+                .get("x-request-id")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            ..Self::default()
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor: Option<String>,
+    pub impersonator: Option<String>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: String,
+    pub diff: Value,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only entries older than this id, for paging.
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+/// `{"field": {"before": .., "after": ..}}` for every field that differs.
+pub fn diff(before: Option<&Value>, after: Option<&Value>) -> Value {
+    let empty = Map::new();
+    let before = before.and_then(Value::as_object).unwrap_or(&empty);
+    let after = after.and_then(Value::as_object).unwrap_or(&empty);
+    let mut changes = Map::new();
+    for key in before.keys().chain(after.keys()) {
+        if IGNORED_FIELDS.contains(&key.as_str()) || changes.contains_key(key) {
+            continue;
+        }
+        let old = before.get(key).unwrap_or(&Value::Null);
+        let new = after.get(key).unwrap_or(&Value::Null);
+        if old != new {
+            changes.insert(key.clone(), json!({ "before": old, "after": new }));
+        }
+    }
+    Value::Object(changes)
+}
+
+impl State {
+    /// Records a change of `target_type` `target_id`; a failure is logged, never returned,
+    /// since the change itself already happened.
+    pub async fn audit<T: Serialize + Sync>(
+        &self,
+        audit: &Audit,
+        action: &str,
+        target_type: &str,
+        target_id: impl ToString + Send,
+        before: Option<&T>,
+        after: Option<&T>,
+    ) {
+        let snapshot = |value: Option<&T>| value.and_then(|v| serde_json::to_value(v).ok());
+        let changes = diff(snapshot(before).as_ref(), snapshot(after).as_ref());
+        let inserted = sqlx::query!(
+            "INSERT INTO audit_log
+                (actor, impersonator, action, target_type, target_id, diff, ip, request_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            audit.actor,
+            audit.impersonator,
+            action,
+            target_type,
+            target_id.to_string(),
+            changes,
+            audit.ip,
+            audit.request_id,
+        )
+        .execute(&self.pg)
+        .await;
+        if let Err(e) = inserted {
+            warn!("failed to write audit entry for {action}: {e}");
+        }
+    }
+    pub async fn audit_entries(&self, filter: AuditFilter) -> Result<Vec<AuditEntry>> {
+        Ok(query_as!(
+            AuditEntry,
+            "SELECT id, actor, impersonator, action, target_type, target_id, diff, ip, request_id, created_at
+            FROM audit_log
+            WHERE ($1::VARCHAR IS NULL OR actor = $1)
+            AND ($2::VARCHAR IS NULL OR action = $2)
+            AND ($3::VARCHAR IS NULL OR target_type = $3)
+            AND ($4::VARCHAR IS NULL OR target_id = $4)
+            AND ($5::TIMESTAMPTZ IS NULL OR created_at >= $5)
+            AND ($6::TIMESTAMPTZ IS NULL OR created_at < $6)
+            AND ($7::BIGINT IS NULL OR id < $7)
+            ORDER BY id DESC
+            LIMIT $8",
+            filter.actor,
+            filter.action,
+            filter.target_type,
+            filter.target_id,
+            filter.since,
+            filter.until,
+            filter.before,
+            filter.limit.unwrap_or(100).clamp(1, 1000),
+        )
+        .fetch_all(&self.pg)
+        .await?)
+    }
+}
+
+#[test]
+fn diff_t() {
+    let before = json!({ "name": "a", "price": 1, "executable": [77, 90] });
+    let after = json!({ "name": "b", "price": 1, "executable": [77, 90, 0] });
+    assert_eq!(
+        diff(Some(&before), Some(&after)),
+        json!({ "name": { "before": "a", "after": "b" } })
+    );
+    assert_eq!(
+        diff(None, Some(&json!({ "id": 1, "rating": null }))),
+        json!({ "id": { "before": null, "after": 1 } })
+    );
+    assert_eq!(diff(Some(&before), Some(&before)), json!({}));
+}
+
+#[tokio::test]
+async fn audit_t() {
+    let state = crate::test::state().await;
+    let audit = Audit {
+        actor: Some("auditor".to_string()),
+        request_id: Some(uuid::Uuid::new_v4().to_string()),
+        ..Audit::default()
+    };
+    let before = json!({ "name": "old" });
+    let after = json!({ "name": "new" });
+    state
+        .audit(
+            &audit,
+            "product.update",
+            "product",
+            -1,
+            Some(&before),
+            Some(&after),
+        )
+        .await;
+
+    let entries = state
+        .audit_entries(AuditFilter {
+            actor: Some("auditor".to_string()),
+            action: Some("product.update".to_string()),
+            limit: Some(1),
+            ..AuditFilter::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(entries[0].request_id, audit.request_id);
+    assert_eq!(entries[0].diff["name"]["after"], "new");
+
+    // append-only
+    assert!(
+        sqlx::query("DELETE FROM audit_log WHERE id = $1")
+            .bind(entries[0].id)
+            .execute(&state.pg)
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,103 @@
+#![deny(warnings)]
+#![deny(clippy::all)]
+#![deny(clippy::pedantic)]
+#![deny(clippy::nursery)]
+
+use clap::{Parser, Subcommand};
+use devmarket::{Cli, Config, Error, Result, State, connect, migrate, pending_migrations};
+use dotenvy::dotenv;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+/// Maintenance tasks for a `DevMarket` deployment. Passwords are read from
+/// `DEVMARKET_ADMIN_PASSWORD`, a prompt, or the first line of stdin, never from arguments.
+#[derive(Parser)]
+struct Admin {
+    /// TOML configuration file, same as the server's.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[arg(long, global = true)]
+    database_url: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+#[derive(Subcommand)]
+enum Command {
+    /// Apply the pending migrations.
+    Migrate,
+    /// Create an admin account with a verified email.
+    CreateAdmin {
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        username: String,
+    },
+    /// Set a new password and sign the user out everywhere.
+    ResetPassword { username: String },
+    /// Create demo accounts, products and a purchase.
+    SeedDemo,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+    let admin = Admin::parse();
+    let cli = Cli {
+        config: admin.config,
+        database_url: admin.database_url,
+        ..Cli::default()
+    };
+    let config = Config::from_sources(&cli, std::env::vars())?;
+    let pool = connect(&config).await?;
+    if matches!(admin.command, Command::Migrate) {
+        migrate(&pool).await?;
+    }
+    let pending = pending_migrations(&pool).await?;
+    if !pending.is_empty() {
+        return Err(Error::SchemaBehind(pending));
+    }
+    run(&State::new(config, pool).await?, admin.command).await
+}
+/// Keeps passwords out of `ps` output and shell history.
+fn read_password() -> Result<String> {
+    if let Ok(password) = std::env::var("DEVMARKET_ADMIN_PASSWORD") {
+        return Ok(password);
+    }
+    if std::io::stdin().is_terminal() {
+        return Ok(rpassword::prompt_password("password: ")?);
+    }
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+async fn run(state: &State, command: Command) -> Result<()> {
+    match command {
+        Command::Migrate => {
+            let moved = state.move_legacy_blobs().await?;
+            println!("database schema is up to date, {moved} executables moved to the blob store");
+        }
+        Command::CreateAdmin { email, username } => {
+            let user = state
+                .create_admin(email, username, read_password()?)
+                .await?;
+            println!("admin {} created with id {}", user.username, user.id);
+        }
+        Command::ResetPassword { username } => {
+            state
+                .set_password(username.clone(), &read_password()?)
+                .await?;
+            println!("password of {username} changed, their sessions were revoked");
+        }
+        Command::SeedDemo => {
+            if state.seed_demo().await? {
+                println!(
+                    "demo data seeded: demoseller and demobuyer, password {}",
+                    devmarket::DEMO_PASSWORD
+                );
+            } else {
+                println!("demo data already present");
+            }
+        }
+    }
+    Ok(())
+}
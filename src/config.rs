@@ -0,0 +1,507 @@
+use crate::error::{Error, Result};
+use clap::Parser;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+/// Read when `--config` isn't given, skipped if it doesn't exist.
+const DEFAULT_FILE: &str = "devmarket.toml";
+/// `DEVMARKET__DATABASE__MAX_CONNECTIONS=10` sets `database.max_connections`.
+const ENV_PREFIX: &str = "DEVMARKET__";
+/// Shown instead of secrets when a config is logged.
+const REDACTED: &str = "<redacted>";
+/// Variables the service read before it had a config file.
+const LEGACY_ENV: [(&str, &str); 3] = [
+    ("DATABASE_URL", "database.url"),
+    ("JWT_KEYS_DIR", "storage.keys"),
+    ("MAIL_DIR", "storage.mail"),
+];
+
+#[derive(Debug, Default, Parser)]
+#[command(about = "DevMarket API server")]
+pub struct Cli {
+    /// TOML configuration file.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, e.g. 0.0.0.0:8080.
+    #[arg(long)]
+    pub bind: Option<String>,
+    #[arg(long)]
+    pub database_url: Option<String>,
+    #[arg(long)]
+    pub max_connections: Option<u32>,
+    /// Apply pending migrations before serving.
+    #[arg(long)]
+    pub migrate: bool,
+    /// Any other setting, e.g. `--set tokens.access_ttl_minutes=15`.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+}
+
+/// Settings of the whole service: defaults, then the TOML file, then
+/// `DEVMARKET__*` variables, then command line flags.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub tokens: TokenConfig,
+    pub uploads: UploadConfig,
+    pub storage: StorageConfig,
+    /// Blobs go to this bucket instead of `storage.blobs` when set.
+    pub s3: Option<S3Config>,
+    /// Login providers by name, e.g. `[oidc.github]` serves `/auth/oidc/github/*`.
+    pub oidc: BTreeMap<String, OidcConfig>,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    /// How long in-flight requests get to finish once a shutdown signal arrives.
+    pub shutdown_timeout_secs: u64,
+}
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout_secs: u64,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenConfig {
+    pub access_ttl_minutes: i64,
+    pub mfa_ttl_minutes: i64,
+    pub refresh_ttl_days: i64,
+    pub impersonation_ttl_minutes: i64,
+    pub reset_ttl_minutes: i64,
+    pub verify_ttl_hours: i64,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    /// Largest request body accepted, artifact uploads aside.
+    pub max_bytes: usize,
+    /// Largest executable, sent at once or in chunks.
+    pub artifact_max_bytes: i64,
+    /// Largest chunk of a resumable upload.
+    pub chunk_max_bytes: i64,
+    /// How long an unfinished resumable upload can be continued.
+    pub resumable_ttl_hours: i64,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Signing keys, see `KeyRing`.
+    pub keys: PathBuf,
+    /// Outgoing mail when delivered to files.
+    pub mail: PathBuf,
+    /// Uploaded files, unless they go to S3.
+    pub blobs: PathBuf,
+    /// Uploads in progress.
+    pub uploads: PathBuf,
+}
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    /// e.g. `http://localhost:9000` for a local `MinIO`.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+/// An authorization-code provider.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    pub authorize_url: String,
+    pub token_url: String,
+    pub userinfo_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    pub scopes: String,
+}
+
+/// The URL carries the database password.
+impl std::fmt::Debug for DatabaseConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DatabaseConfig")
+            .field("url", &REDACTED)
+            .field("max_connections", &self.max_connections)
+            .field("min_connections", &self.min_connections)
+            .field("acquire_timeout_secs", &self.acquire_timeout_secs)
+            .finish()
+    }
+}
+impl std::fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("access_key", &self.access_key)
+            .field("secret_key", &REDACTED)
+            .finish()
+    }
+}
+impl std::fmt::Debug for OidcConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcConfig")
+            .field("authorize_url", &self.authorize_url)
+            .field("token_url", &self.token_url)
+            .field("userinfo_url", &self.userinfo_url)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &REDACTED)
+            .field("redirect_uri", &self.redirect_uri)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            max_connections: 5,
+            min_connections: 0,
+            acquire_timeout_secs: 30,
+        }
+    }
+}
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            access_ttl_minutes: 60,
+            mfa_ttl_minutes: 5,
+            refresh_ttl_days: 30,
+            impersonation_ttl_minutes: 15,
+            reset_ttl_minutes: 30,
+            verify_ttl_hours: 48,
+        }
+    }
+}
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024 * 1024,
+            artifact_max_bytes: 512 * 1024 * 1024,
+            chunk_max_bytes: 16 * 1024 * 1024,
+            resumable_ttl_hours: 24,
+        }
+    }
+}
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            keys: "keys".into(),
+            mail: "mail".into(),
+            blobs: "blobs".into(),
+            uploads: "uploads".into(),
+        }
+    }
+}
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: "https://s3.amazonaws.com".to_string(),
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            access_key: String::new(),
+            secret_key: String::new(),
+        }
+    }
+}
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            authorize_url: String::new(),
+            token_url: String::new(),
+            userinfo_url: String::new(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            redirect_uri: String::new(),
+            scopes: "openid email profile".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_sources(
+        cli: &Cli,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let mut table = match &cli.config {
+            Some(path) => read_file(path)?,
+            None if std::path::Path::new(DEFAULT_FILE).exists() => {
+                read_file(DEFAULT_FILE.as_ref())?
+            }
+            None => Table::new(),
+        };
+        let defaults =
+            Table::try_from(Self::default()).map_err(|e| Error::Config(e.to_string()))?;
+        for (key, value) in env {
+            if let Some((_, path)) = LEGACY_ENV.iter().find(|(name, _)| *name == key) {
+                set(&mut table, &defaults, path, &value)?;
+            } else if let Some(path) = key.strip_prefix(ENV_PREFIX) {
+                set(
+                    &mut table,
+                    &defaults,
+                    &path.to_lowercase().replace("__", "."),
+                    &value,
+                )?;
+            }
+        }
+        for (path, value) in cli.flags() {
+            set(&mut table, &defaults, &path, &value)?;
+        }
+        let config: Self = table
+            .try_into()
+            .map_err(|e: toml::de::Error| Error::Config(e.message().to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+    fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(Error::Config(reason.to_string()));
+        let database = &self.database;
+        if database.url.is_empty() {
+            return invalid("database.url is required");
+        }
+        if database.max_connections == 0 || database.min_connections > database.max_connections {
+            return invalid("database.max_connections must be at least 1 and min_connections");
+        }
+        let (tokens, uploads) = (&self.tokens, &self.uploads);
+        // bounded so the expiries, and the comparison below, stay far from overflowing
+        let ttls = [
+            ("tokens.access_ttl_minutes", tokens.access_ttl_minutes, 1440),
+            ("tokens.mfa_ttl_minutes", tokens.mfa_ttl_minutes, 60),
+            ("tokens.refresh_ttl_days", tokens.refresh_ttl_days, 365),
+            (
+                "tokens.impersonation_ttl_minutes",
+                tokens.impersonation_ttl_minutes,
+                240,
+            ),
+            ("tokens.reset_ttl_minutes", tokens.reset_ttl_minutes, 1440),
+            ("tokens.verify_ttl_hours", tokens.verify_ttl_hours, 720),
+            (
+                "uploads.resumable_ttl_hours",
+                uploads.resumable_ttl_hours,
+                720,
+            ),
+        ];
+        if let Some((name, _, max)) = ttls.iter().find(|(_, ttl, max)| !(1..=*max).contains(ttl)) {
+            return invalid(&format!("{name} must be between 1 and {max}"));
+        }
+        if tokens.access_ttl_minutes >= tokens.refresh_ttl_days * 24 * 60 {
+            return invalid("tokens.access_ttl_minutes must be shorter than refresh_ttl_days");
+        }
+        if uploads.max_bytes == 0 || uploads.artifact_max_bytes <= 0 || uploads.chunk_max_bytes <= 0
+        {
+            return invalid("upload limits must be positive");
+        }
+        if let Some(s3) = &self.s3
+            && (s3.bucket.is_empty() || s3.access_key.is_empty() || s3.secret_key.is_empty())
+        {
+            return invalid("s3.bucket, s3.access_key and s3.secret_key are required");
+        }
+        for (name, oidc) in &self.oidc {
+            let urls = [
+                &oidc.authorize_url,
+                &oidc.token_url,
+                &oidc.userinfo_url,
+                &oidc.redirect_uri,
+            ];
+            if urls.iter().any(|url| Url::parse(url).is_err())
+                || oidc.client_id.is_empty()
+                || oidc.client_secret.is_empty()
+            {
+                return invalid(&format!(
+                    "oidc.{name} needs valid URLs, a client_id and a client_secret"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Cli {
+    fn flags(&self) -> Vec<(String, String)> {
+        let mut flags: Vec<(String, String)> = [
+            ("server.bind", self.bind.clone()),
+            ("database.url", self.database_url.clone()),
+            (
+                "database.max_connections",
+                self.max_connections.map(|n| n.to_string()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(path, value)| Some((path.to_string(), value?)))
+        .collect();
+        flags.extend(self.overrides.iter().map(|pair| {
+            let (path, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (path.to_string(), value.to_string())
+        }));
+        flags
+    }
+}
+
+fn read_file(path: &std::path::Path) -> Result<Table> {
+    std::fs::read_to_string(path)?
+        .parse()
+        .map_err(|e: toml::de::Error| Error::Config(format!("{}: {}", path.display(), e.message())))
+}
+
+/// Sets `section.key`, or `section.name.key` in named tables like `oidc.github`, typed
+/// like the default of the setting; settings without one, like those of `s3`, are strings.
+fn set(table: &mut Table, defaults: &Table, path: &str, raw: &str) -> Result<()> {
+    let keys: Vec<&str> = path.split('.').collect();
+    let Some((key, sections)) = keys
+        .split_last()
+        .filter(|(_, sections)| !sections.is_empty())
+    else {
+        return Err(Error::Config(format!("unknown setting {path}")));
+    };
+    let invalid = |kind: &str| Error::Config(format!("{path} must be {kind}"));
+    let default = sections
+        .iter()
+        .try_fold(defaults, |table, name| table.get(*name)?.as_table())
+        .and_then(|section| section.get(*key));
+    let value = match default {
+        Some(Value::Integer(_)) => Value::Integer(raw.parse().map_err(|_| invalid("a number"))?),
+        Some(Value::Boolean(_)) => Value::Boolean(raw.parse().map_err(|_| invalid("a boolean"))?),
+        _ => Value::String(raw.to_string()),
+    };
+    let mut section = table;
+    for name in sections {
+        let Value::Table(inner) = section
+            .entry(*name)
+            .or_insert_with(|| Value::Table(Table::new()))
+        else {
+            return Err(Error::Config(format!("{name} isn't a section")));
+        };
+        section = inner;
+    }
+    section.insert((*key).to_string(), value);
+    Ok(())
+}
+
+#[test]
+fn config_t() {
+    let dir = std::env::temp_dir().join(format!("devmarket-config-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("devmarket.toml");
+    std::fs::write(
+        &file,
+        "[server]\nbind = \"0.0.0.0:9000\"\n[database]\nurl = \"postgres://file\"\nmax_connections = 8\n",
+    )
+    .unwrap();
+    let env = |pairs: &[(&str, &str)]| {
+        pairs
+            .iter()
+            .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+            .collect::<Vec<_>>()
+    };
+    let cli = Cli {
+        config: Some(file),
+        max_connections: Some(12),
+        overrides: vec!["tokens.access_ttl_minutes=15".to_string()],
+        ..Cli::default()
+    };
+
+    let config = Config::from_sources(
+        &cli,
+        env(&[
+            ("DATABASE_URL", "postgres://env"),
+            ("DEVMARKET__DATABASE__MAX_CONNECTIONS", "10"),
+            ("DEVMARKET__UPLOADS__MAX_BYTES", "1024"),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(config.server.bind.port(), 9000);
+    assert_eq!(config.database.url, "postgres://env");
+    assert_eq!(config.database.max_connections, 12);
+    assert_eq!(config.uploads.max_bytes, 1024);
+    assert_eq!(config.tokens.access_ttl_minutes, 15);
+    assert_eq!(config.tokens.refresh_ttl_days, 30);
+    assert!(config.s3.is_none());
+
+    let zero = env(&[("DEVMARKET__TOKENS__MFA_TTL_MINUTES", "0")]);
+    assert!(Config::from_sources(&cli, zero).is_err());
+    let typo = env(&[("DEVMARKET__SERVER__PORT", "80")]);
+    assert!(Config::from_sources(&cli, typo).is_err());
+    let bucket = env(&[("DEVMARKET__S3__BUCKET", "devmarket")]);
+    assert!(Config::from_sources(&cli, bucket).is_err());
+    let s3 = env(&[
+        ("DEVMARKET__S3__BUCKET", "devmarket"),
+        ("DEVMARKET__S3__ACCESS_KEY", "minio"),
+        ("DEVMARKET__S3__SECRET_KEY", "minio123"),
+    ]);
+    let s3 = Config::from_sources(&cli, s3).unwrap().s3.unwrap();
+    assert_eq!(
+        (s3.bucket.as_str(), s3.region.as_str()),
+        ("devmarket", "us-east-1")
+    );
+    // values that look like numbers stay strings where the setting is one
+    let numeric = env(&[
+        ("DEVMARKET__S3__BUCKET", "2024"),
+        ("DEVMARKET__S3__ACCESS_KEY", "true"),
+        ("DEVMARKET__S3__SECRET_KEY", "12345678"),
+    ]);
+    let config = Config::from_sources(&cli, numeric).unwrap();
+    assert_eq!(config.s3.as_ref().unwrap().secret_key, "12345678");
+    let logged = format!("{config:?}");
+    assert!(!logged.contains("12345678") && !logged.contains("postgres://env"));
+    let words = env(&[("DEVMARKET__DATABASE__MAX_CONNECTIONS", "ten")]);
+    assert!(Config::from_sources(&cli, words).is_err());
+    let forever = env(&[("DEVMARKET__TOKENS__REFRESH_TTL_DAYS", "9223372036854775807")]);
+    assert!(Config::from_sources(&cli, forever).is_err());
+    let stale = env(&[("DEVMARKET__UPLOADS__RESUMABLE_TTL_HOURS", "100000")]);
+    assert!(Config::from_sources(&cli, stale).is_err());
+
+    let github = |secret: &str| {
+        let mut pairs = vec![
+            (
+                "DEVMARKET__OIDC__GITHUB__AUTHORIZE_URL",
+                "https://github.com/login/oauth/authorize",
+            ),
+            (
+                "DEVMARKET__OIDC__GITHUB__TOKEN_URL",
+                "https://github.com/login/oauth/access_token",
+            ),
+            (
+                "DEVMARKET__OIDC__GITHUB__USERINFO_URL",
+                "https://api.github.com/user",
+            ),
+            ("DEVMARKET__OIDC__GITHUB__CLIENT_ID", "devmarket"),
+            (
+                "DEVMARKET__OIDC__GITHUB__REDIRECT_URI",
+                "https://devmarket.test/auth/oidc/github/callback",
+            ),
+        ];
+        if !secret.is_empty() {
+            pairs.push(("DEVMARKET__OIDC__GITHUB__CLIENT_SECRET", secret));
+        }
+        env(&pairs)
+    };
+    assert!(Config::from_sources(&cli, github("")).is_err());
+    let config = Config::from_sources(&cli, github("gh-secret")).unwrap();
+    let provider = &config.oidc["github"];
+    assert_eq!(provider.client_id, "devmarket");
+    assert_eq!(provider.scopes, "openid email profile");
+    assert!(!format!("{config:?}").contains("gh-secret"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
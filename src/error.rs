@@ -1,79 +1,557 @@
-use std::{env::VarError, io::Error as StdError, num::TryFromIntError};
+use std::{collections::BTreeMap, io::Error as StdError, num::TryFromIntError};
 
-use axum::{http::StatusCode, response::IntoResponse};
+use crate::products::format::Platform;
+use axum::{
+    Json,
+    extract::{
+        Request,
+        multipart::MultipartError,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+    },
+    http::{
+        HeaderValue, StatusCode,
+        header::{CONTENT_RANGE, RETRY_AFTER},
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
 use bcrypt::BcryptError;
 use jsonwebtoken::errors::Error as JwtError;
+use serde::Serialize;
+use serde_json::{Value, json};
 use thiserror::Error;
+use validator::ValidationErrors;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Database error: {0}")]
-    Sql(#[from] sqlx::Error),
+    Sql(sqlx::Error),
 
-    #[error("DB connection error")]
-    Cn(#[from] VarError),
+    #[error("Migration failed: {0}")]
+    Migrate(#[from] sqlx::migrate::MigrateError),
+
+    #[error("Database schema is behind, run with --migrate to apply {0:?}")]
+    SchemaBehind(Vec<i64>),
+
+    #[error("Not found")]
+    NotFound,
+
+    #[error("Username already taken")]
+    UsernameTaken,
+
+    #[error("Email already taken")]
+    EmailTaken,
+
+    #[error("Conflict on {0}")]
+    Conflict(String),
 
     #[error("IO error")]
     Io(#[from] StdError),
 
+    #[error("Blob store error: {0}")]
+    Storage(String),
+
+    #[error("Archive error")]
+    Zip(#[from] zip::result::ZipError),
+
     #[error("bcrypt error")]
     Bypt(#[from] BcryptError),
 
     #[error("JWT error")]
-    JWT(#[from] JwtError),
+    Jwt(#[from] JwtError),
+
+    #[error("Not logged in")]
+    NotLoggedIn,
 
     #[error("Invalid user")]
     InvalidUser,
 
+    #[error("Email not verified")]
+    EmailNotVerified,
+
+    #[error("Two-factor login not finished")]
+    MfaPending,
+
+    #[error("Invalid two-factor code")]
+    InvalidMfaCode,
+
+    #[error("Two-factor authentication already enabled")]
+    MfaAlreadyEnabled,
+
+    #[error("Two-factor authentication required")]
+    MfaRequired,
+
+    #[error("Too many failed attempts, retry in {0}s")]
+    TooManyAttempts(u64),
+
+    #[error("Not allowed while impersonating")]
+    ImpersonationForbidden,
+
+    #[error("Invalid product transfer")]
+    InvalidTransfer,
+
+    #[error("Invalid API key")]
+    InvalidApiKey,
+
+    #[error("API key used for account management")]
+    ApiKeyForbidden,
+
+    #[error("Missing API key scope")]
+    MissingScope,
+
+    #[error("Invalid token")]
+    InvalidToken,
+
+    #[error("Invalid signing key")]
+    InvalidKey,
+
+    #[error("Unknown identity provider")]
+    UnknownProvider,
+
+    #[error("Identity provider error")]
+    IdentityProvider,
+
+    #[error("Identity provider request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Identity already linked")]
+    IdentityConflict,
+
     #[error("JSON error")]
     Json(#[from] serde_json::Error),
 
-    #[error("Data type error")]
+    #[error("Invalid request body: {0}")]
+    InvalidBody(#[from] JsonRejection),
+
+    #[error("Invalid path: {0}")]
+    InvalidPath(#[from] PathRejection),
+
+    #[error("Invalid query: {0}")]
+    InvalidQuery(#[from] QueryRejection),
+
+    #[error("Invalid fields: {0}")]
+    Validation(#[from] ValidationErrors),
+
+    #[error("Unsupported file format")]
     Datatype,
 
+    #[error("Unknown platform {0}")]
+    UnknownPlatform(String),
+
+    #[error("Built for {detected:?}, not {declared}")]
+    PlatformMismatch {
+        declared: Platform,
+        detected: Vec<Platform>,
+    },
+
+    #[error("Product not purchased")]
+    NotPurchased,
+
+    #[error("No artifact for the platform")]
+    NoArtifact,
+
+    #[error("Range outside of the {0} bytes file")]
+    RangeNotSatisfiable(u64),
+
+    #[error("Upload larger than {0} bytes")]
+    TooLarge(i64),
+
+    #[error("Invalid upload: {0}")]
+    InvalidUpload(&'static str),
+
+    #[error("Invalid multipart body: {0}")]
+    Multipart(#[from] MultipartError),
+
+    #[error("Upload continues at offset {0}")]
+    UploadOffset(i64),
+
+    #[error("Uploaded file doesn't match its SHA-256")]
+    ChecksumMismatch,
+
     #[error("Conversion error")]
     Conversion(#[from] TryFromIntError),
 
     #[error("env error")]
     Env(#[from] dotenvy::Error),
+
+    #[error("Invalid configuration: {0}")]
+    Config(String),
 }
 
-impl IntoResponse for Error {
-    fn into_response(self) -> axum::response::Response {
-        let (status, message) = match self {
-            Self::Sql(_) | Self::Cn(_) => (
+/// Unique constraints that have an error of their own, the others are a plain `Conflict`.
+fn unique_violation(constraint: &str) -> Error {
+    match constraint {
+        "User_username_key" => Error::UsernameTaken,
+        "User_email_key" => Error::EmailTaken,
+        "user_identity_provider_subject_key" | "user_identity_user_id_provider_key" => {
+            Error::IdentityConflict
+        }
+        _ => Error::Conflict(constraint.to_string()),
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(error: sqlx::Error) -> Self {
+        match &error {
+            sqlx::Error::RowNotFound => Self::NotFound,
+            sqlx::Error::Database(db) if db.is_unique_violation() => {
+                unique_violation(db.constraint().unwrap_or_default())
+            }
+            _ => Self::Sql(error),
+        }
+    }
+}
+
+/// The body of every error response; `code` is stable, `message` is for humans.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: &'static str,
+    pub details: Value,
+    pub request_id: Option<String>,
+}
+
+/// `{"field": ["message", ..]}`, falling back to the error code when there's no message.
+fn field_errors(errors: &ValidationErrors) -> BTreeMap<&str, Vec<String>> {
+    errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errors)| {
+            let messages = errors
+                .iter()
+                .map(|error| {
+                    error
+                        .message
+                        .as_ref()
+                        .map_or_else(|| error.code.to_string(), ToString::to_string)
+                })
+                .collect();
+            (field, messages)
+        })
+        .collect()
+}
+
+impl Error {
+    /// Status, code and message of the response.
+    #[allow(clippy::too_many_lines)]
+    const fn status(&self) -> (StatusCode, &'static str, &'static str) {
+        match self {
+            Self::Sql(_) | Self::Migrate(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
+                "database_error",
                 "Something went wrong with the database or its connection",
             ),
-            Self::Io(_) => (
+            Self::SchemaBehind(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "schema_behind",
+                "The database schema is out of date",
+            ),
+            Self::NotFound => (
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "The requested resource doesn't exist",
+            ),
+            Self::UsernameTaken => (
+                StatusCode::CONFLICT,
+                "username_taken",
+                "This username is already taken",
+            ),
+            Self::EmailTaken => (
+                StatusCode::CONFLICT,
+                "email_taken",
+                "An account already uses this email address",
+            ),
+            Self::Conflict(_) => (
+                StatusCode::CONFLICT,
+                "conflict",
+                "This conflicts with an existing resource",
+            ),
+            Self::Io(_) | Self::Zip(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
+                "io_error",
                 "I/O error occurred while connecting to the server",
             ),
-            Self::Bypt(_) | Self::JWT(_) => (
+            Self::Storage(_) => (
+                StatusCode::BAD_GATEWAY,
+                "storage_error",
+                "The file storage didn't answer as expected",
+            ),
+            Self::Bypt(_) | Self::Jwt(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
+                "auth_error",
                 "Authentication failed while processing the user",
             ),
+            Self::NotLoggedIn => (
+                StatusCode::UNAUTHORIZED,
+                "not_logged_in",
+                "You're not allowed to be in here, please login",
+            ),
             Self::InvalidUser => (
                 StatusCode::FORBIDDEN,
+                "forbidden",
                 "You are not authorized to access this resource",
             ),
-            Self::Json(_) => (
+            Self::EmailNotVerified => (
+                StatusCode::FORBIDDEN,
+                "email_not_verified",
+                "Please verify your email address first",
+            ),
+            Self::MfaPending => (
+                StatusCode::UNAUTHORIZED,
+                "mfa_pending",
+                "Please finish the two-factor login first",
+            ),
+            Self::InvalidMfaCode => (
+                StatusCode::UNAUTHORIZED,
+                "invalid_mfa_code",
+                "The two-factor code is invalid or was already used",
+            ),
+            Self::MfaAlreadyEnabled => (
+                StatusCode::CONFLICT,
+                "mfa_already_enabled",
+                "Two-factor authentication is already enabled",
+            ),
+            Self::MfaRequired => (
+                StatusCode::FORBIDDEN,
+                "mfa_required",
+                "Two-factor authentication is required for your role",
+            ),
+            Self::TooManyAttempts(_) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "too_many_attempts",
+                "Too many failed attempts, please try again later",
+            ),
+            Self::ImpersonationForbidden => (
+                StatusCode::FORBIDDEN,
+                "impersonation_forbidden",
+                "This action can't be taken while impersonating a user",
+            ),
+            Self::InvalidTransfer => (
                 StatusCode::BAD_REQUEST,
+                "invalid_transfer",
+                "Products can only be transferred to another active seller",
+            ),
+            Self::InvalidApiKey => (
+                StatusCode::UNAUTHORIZED,
+                "invalid_api_key",
+                "Invalid, expired or revoked API key",
+            ),
+            Self::ApiKeyForbidden => (
+                StatusCode::FORBIDDEN,
+                "api_key_forbidden",
+                "API keys can't be used to manage accounts",
+            ),
+            Self::MissingScope => (
+                StatusCode::FORBIDDEN,
+                "missing_scope",
+                "This API key doesn't have the scope required for this action",
+            ),
+            Self::InvalidToken => (
+                StatusCode::UNAUTHORIZED,
+                "invalid_token",
+                "The token is invalid, expired or has been revoked",
+            ),
+            Self::InvalidKey => (
+                StatusCode::BAD_REQUEST,
+                "invalid_signing_key",
+                "Unknown, malformed or still active signing key",
+            ),
+            Self::UnknownProvider => (
+                StatusCode::NOT_FOUND,
+                "unknown_provider",
+                "Unknown identity provider",
+            ),
+            Self::IdentityProvider | Self::Http(_) => (
+                StatusCode::BAD_GATEWAY,
+                "identity_provider_error",
+                "The identity provider didn't complete the login",
+            ),
+            Self::IdentityConflict => (
+                StatusCode::CONFLICT,
+                "identity_conflict",
+                "This identity or email already belongs to another account, sign in and link it instead",
+            ),
+            Self::Json(_) | Self::InvalidBody(_) => (
+                StatusCode::BAD_REQUEST,
+                "invalid_json",
                 "Invalid JSON format in the request",
             ),
+            Self::InvalidPath(_) => (
+                StatusCode::BAD_REQUEST,
+                "invalid_path",
+                "A parameter in the path is malformed",
+            ),
+            Self::InvalidQuery(_) => (
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "Invalid query string",
+            ),
+            Self::Validation(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "validation_failed",
+                "Some fields of the request are invalid",
+            ),
             Self::Datatype => (
                 StatusCode::BAD_REQUEST,
-                "Expected an executable (.exe) file",
+                "invalid_executable",
+                "Expected a Windows, Linux or macOS executable, a package or an archive",
             ),
-            Self::Conversion(_) => (StatusCode::BAD_REQUEST, "Failed to convert number type"),
-            Self::Env(_) => (
+            Self::UnknownPlatform(_) => (
+                StatusCode::BAD_REQUEST,
+                "unknown_platform",
+                "Platforms are an OS, optionally with an architecture, like linux-x86_64",
+            ),
+            Self::PlatformMismatch { .. } => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "platform_mismatch",
+                "The file isn't built for a declared platform",
+            ),
+            Self::NotPurchased => (
+                StatusCode::FORBIDDEN,
+                "not_purchased",
+                "Buy this product to download it",
+            ),
+            Self::NoArtifact => (
+                StatusCode::NOT_FOUND,
+                "no_matching_artifact",
+                "No build of the product runs on this platform",
+            ),
+            Self::RangeNotSatisfiable(_) => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "range_not_satisfiable",
+                "The requested range is outside of the file",
+            ),
+            Self::TooLarge(_) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                "The upload is larger than allowed",
+            ),
+            Self::InvalidUpload(_) | Self::Multipart(_) => (
+                StatusCode::BAD_REQUEST,
+                "invalid_upload",
+                "The upload request is malformed",
+            ),
+            Self::UploadOffset(_) => (
+                StatusCode::CONFLICT,
+                "upload_offset_mismatch",
+                "The chunk doesn't start where the upload stopped",
+            ),
+            Self::ChecksumMismatch => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "checksum_mismatch",
+                "The uploaded file doesn't match the announced SHA-256",
+            ),
+            Self::Conversion(_) => (
+                StatusCode::BAD_REQUEST,
+                "invalid_number",
+                "Failed to convert number type",
+            ),
+            Self::Env(_) | Self::Config(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "A problem has occured when reading the env file",
+                "config_error",
+                "A problem has occured when reading the configuration",
             ),
-        };
+        }
+    }
+    /// Whatever helps to act on the error, `null` for most of them.
+    fn details(&self) -> Value {
+        match self {
+            Self::Validation(errors) => json!({ "fields": field_errors(errors) }),
+            Self::TooManyAttempts(secs) => json!({ "retry_after": secs }),
+            Self::Conflict(constraint) => json!({ "constraint": constraint }),
+            Self::InvalidBody(rejection) => json!({ "reason": rejection.body_text() }),
+            Self::InvalidPath(rejection) => json!({ "reason": rejection.body_text() }),
+            Self::InvalidQuery(rejection) => json!({ "reason": rejection.body_text() }),
+            Self::InvalidUpload(reason) => json!({ "reason": reason }),
+            Self::Multipart(e) => json!({ "reason": e.body_text() }),
+            Self::TooLarge(max) => json!({ "max_bytes": max }),
+            Self::RangeNotSatisfiable(size) => json!({ "size": size }),
+            Self::UploadOffset(offset) => json!({ "offset": offset }),
+            Self::UnknownPlatform(platform) => json!({ "platform": platform }),
+            Self::PlatformMismatch { declared, detected } => {
+                json!({ "declared": declared.to_string(), "detected": detected })
+            }
+            _ => Value::Null,
+        }
+    }
+}
 
-        (status, message).into_response()
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let (status, code, message) = self.status();
+        let body = ErrorBody {
+            code,
+            message,
+            details: self.details(),
+            request_id: None,
+        };
+        let mut response = (status, Json(body.clone())).into_response();
+        if let Self::TooManyAttempts(secs) = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        if let Self::RangeNotSatisfiable(size) = self
+            && let Ok(range) = HeaderValue::try_from(format!("bytes */{size}"))
+        {
+            response.headers_mut().insert(CONTENT_RANGE, range);
+        }
+        // picked up by `with_request_id`, which knows the request
+        response.extensions_mut().insert(body);
+        response
     }
 }
+
+/// Fills `request_id` in error bodies with the `X-Request-Id` of the request.
+pub async fn with_request_id(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let response = next.run(request).await;
+    let Some(mut body) = response.extensions().get::<ErrorBody>().cloned() else {
+        return response;
+    };
+    body.request_id = request_id;
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+    (parts, Json(body)).into_response()
+}
+
+#[test]
+fn error_t() {
+    let (status, code, _) = Error::from(sqlx::Error::RowNotFound).status();
+    assert_eq!((status, code), (StatusCode::NOT_FOUND, "not_found"));
+
+    let response = Error::TooManyAttempts(30).into_response();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let body = response.extensions().get::<ErrorBody>().unwrap();
+    assert_eq!(body.code, "too_many_attempts");
+    assert_eq!(body.details["retry_after"], 30);
+}
+
+#[tokio::test]
+async fn rejection_t() {
+    use crate::ext::{JsonBody, Query};
+    use axum::extract::{FromRequest, FromRequestParts};
+    let request = Request::builder()
+        .header("content-type", "application/json")
+        .body(axum::body::Body::from("{"))
+        .unwrap();
+    let rejected = JsonBody::<Value>::from_request(request, &()).await.err();
+    assert!(matches!(rejected, Some(Error::InvalidBody(_))));
+
+    let (mut parts, ()) = Request::builder()
+        .uri("/?page=x")
+        .body(())
+        .unwrap()
+        .into_parts();
+    let rejected = Query::<BTreeMap<String, i32>>::from_request_parts(&mut parts, &())
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(rejected.status().1, "invalid_query");
+}
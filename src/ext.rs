@@ -1,29 +1,203 @@
+use crate::State;
+use crate::error::Error;
 use crate::user::Clains;
-use axum::extract::FromRequestParts;
-use axum::http::StatusCode;
+use crate::user::api_key::Scope;
+use axum::Json;
+use axum::extract::Request;
+use axum::extract::{ConnectInfo, FromRequest, FromRequestParts, State as Extract};
 use axum::http::request::Parts;
+use axum::http::{
+    HeaderValue, Method,
+    header::{AUTHORIZATION, USER_AGENT},
+};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
+use validator::Validate;
 
+/// A fully signed in user.
 pub struct IsAuth(pub Clains);
+/// A user that passed the password check and still owes the second factor.
+pub struct MfaPending(pub Clains);
+/// Either of the above, used by the endpoints that enroll a second factor.
+pub struct MfaSubject(pub Clains);
+/// `axum::Json` as an extractor, a malformed body is an `Error` like any other.
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(Error))]
+pub struct JsonBody<T>(pub T);
+/// `axum::extract::Path` whose rejection is an `Error`.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(Error))]
+pub struct Path<T>(pub T);
+/// `axum::extract::Query` whose rejection is an `Error`.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(Error))]
+pub struct Query<T>(pub T);
+/// A JSON body that passed its `Validate` rules, anything else is a 422 with the failing fields.
+pub struct ValidatedJson<T>(pub T);
+/// Where a request comes from, recorded on the sessions it starts.
+pub struct Device {
+    pub user_agent: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+async fn claims(parts: &Parts, state: &State) -> Result<Clains, Error> {
+    let header = parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if let Some(key) = header.and_then(|value| value.strip_prefix("ApiKey ")) {
+        return state
+            .authenticate_api_key(key)
+            .await
+            .map_err(|_| Error::InvalidApiKey);
+    }
+    if let Some(token_str) = header
+        && let Ok(claims) = Clains::from_token(
+            token_str.strip_prefix("Bearer ").unwrap_or(token_str),
+            &state.keys,
+        )
+    {
+        if state.revoked.is_revoked(&claims) {
+            return Err(Error::InvalidToken);
+        }
+        if let Some(sid) = claims.sid {
+            state.touch_session(sid);
+        }
+        return Ok(claims);
+    }
+
+    Err(Error::NotLoggedIn)
+}
 
 #[async_trait::async_trait]
-impl<S> FromRequestParts<S> for IsAuth
+impl FromRequestParts<State> for IsAuth {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &State) -> Result<Self, Self::Rejection> {
+        let claims = claims(parts, state).await?;
+        if claims.mfa_pending {
+            return Err(Error::MfaPending);
+        }
+        claims.require_scope(method_scope(&parts.method))?;
+        Ok(Self(claims))
+    }
+}
+
+/// Scope an API key needs for a request, reads need `products:read` and everything else
+/// `products:write`; handlers may ask for more.
+fn method_scope(method: &Method) -> Scope {
+    if method.is_safe() {
+        Scope::ProductsRead
+    } else {
+        Scope::ProductsWrite
+    }
+}
+
+#[async_trait::async_trait]
+impl FromRequestParts<State> for MfaPending {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &State) -> Result<Self, Self::Rejection> {
+        let claims = claims(parts, state).await?;
+        if !claims.mfa_pending {
+            return Err(Error::NotLoggedIn);
+        }
+        Ok(Self(claims))
+    }
+}
+
+#[async_trait::async_trait]
+impl FromRequestParts<State> for MfaSubject {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &State) -> Result<Self, Self::Rejection> {
+        Ok(Self(claims(parts, state).await?))
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Device {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            user_agent: parts
+                .headers
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            ip: parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip()),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<S, T> FromRequest<S> for ValidatedJson<T>
 where
     S: Send + Sync,
+    T: DeserializeOwned + Validate,
 {
-    type Rejection = (StatusCode, &'static str);
-
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        if let Some(header_value) = parts.headers.get("Authorization") {
-            if let Ok(token_str) = header_value.to_str() {
-                if let Ok(claims) = Clains::from_token(token_str) {
-                    return Ok(IsAuth(claims));
-                }
-            }
-        }
+    type Rejection = Error;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(request, state)
+            .await
+            .map_err(Error::from)?;
+        value.validate()?;
+        Ok(Self(value))
+    }
+}
 
-        Err((
-            StatusCode::UNAUTHORIZED,
-            "You're not allowed to be in here, please login",
-        ))
+/// Keeps API keys away from account management, they are meant for automation only.
+pub async fn interactive_only(request: Request, next: Next) -> Response {
+    let api_key = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("ApiKey "));
+    if api_key {
+        return Error::ApiKeyForbidden.into_response();
+    }
+    next.run(request).await
+}
+
+/// Marks responses to impersonation tokens with `X-Impersonated-By` and audits every such request.
+pub async fn audit_impersonation(
+    Extract(state): Extract<State>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let claims = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| Clains::from_token(token, &state.keys).ok())
+        .filter(|claims| claims.act.is_some());
+    let Some(claims) = claims else {
+        return next.run(request).await;
+    };
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let mut response = next.run(request).await;
+    let status = response.status().as_u16();
+    if let Err(e) = state
+        .record_impersonated_request(&claims, &method, &path, status)
+        .await
+    {
+        warn!("failed to audit impersonated request: {e}");
+    }
+    if let Some(actor) = claims.act
+        && let Ok(value) = HeaderValue::from_str(&actor.sub)
+    {
+        response.headers_mut().insert("x-impersonated-by", value);
     }
+    response
 }
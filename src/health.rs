@@ -0,0 +1,40 @@
+use crate::State as Mc;
+use crate::schema::pending_migrations;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::{Value, json};
+use tracing::warn;
+
+pub fn health_router() -> Router<Mc> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+/// The process is up and serving requests.
+async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+/// Ready for traffic: the database answers and its schema is up to date.
+async fn readyz(State(mc): State<Mc>) -> (StatusCode, Json<Value>) {
+    let unavailable = |details: Value| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "status": "unavailable", "details": details })),
+        )
+    };
+    if let Err(e) = sqlx::query("SELECT 1").execute(&mc.pg).await {
+        warn!("readiness check failed: {e}");
+        return unavailable(json!({ "database": "unreachable" }));
+    }
+    match pending_migrations(&mc.pg).await {
+        Ok(pending) if pending.is_empty() => (StatusCode::OK, Json(json!({ "status": "ready" }))),
+        Ok(pending) => unavailable(json!({ "pending_migrations": pending })),
+        Err(e) => {
+            warn!("readiness check failed: {e}");
+            unavailable(json!({ "database": "unreachable" }))
+        }
+    }
+}
@@ -0,0 +1,132 @@
+#![deny(warnings)]
+#![deny(clippy::all)]
+#![deny(clippy::pedantic)]
+#![deny(clippy::nursery)]
+// the library only exists so `devmarket-admin` can share the server's code
+#![allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
+
+use axum::{Router, extract::DefaultBodyLimit, middleware, routing::get};
+use sqlx::{PgPool, postgres::PgPoolOptions};
+use std::sync::Arc;
+use std::time::Duration;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tracing::warn;
+#[cfg(test)]
+mod test;
+pub use crate::config::{Cli, Config};
+use crate::error::with_request_id;
+pub use crate::error::{Error, Result};
+use crate::ext::audit_impersonation;
+use crate::health::health_router;
+use crate::mail::{FileMailer, Mailer};
+pub use crate::schema::{migrate, pending_migrations};
+pub use crate::seed::DEMO_PASSWORD;
+use crate::storage::BlobStore;
+use crate::user::{jwks, keys::KeyRing, oidc::Providers, revocation::Revocations};
+use crate::{products::product_route, user::user_router};
+mod audit;
+mod config;
+mod error;
+mod ext;
+mod health;
+mod mail;
+mod policy;
+mod products;
+mod schema;
+mod seed;
+mod storage;
+mod user;
+#[derive(Clone)]
+pub struct State {
+    config: Arc<Config>,
+    pg: PgPool,
+    keys: KeyRing,
+    mailer: Arc<dyn Mailer>,
+    blobs: Arc<dyn BlobStore>,
+    revoked: Revocations,
+    oidc: Providers,
+}
+/// Opens the connection pool described by `config`.
+pub async fn connect(config: &Config) -> Result<PgPool> {
+    Ok(PgPoolOptions::new()
+        .max_connections(config.database.max_connections)
+        .min_connections(config.database.min_connections)
+        .acquire_timeout(Duration::from_secs(config.database.acquire_timeout_secs))
+        .connect(&config.database.url)
+        .await?)
+}
+
+impl State {
+    /// Loads keys, revocations and identity providers; the schema must be up to date.
+    pub async fn new(config: Config, pool: PgPool) -> Result<Self> {
+        Ok(Self {
+            keys: KeyRing::load(&config.storage.keys)?,
+            revoked: Revocations::load(&pool, config.tokens.access_ttl_minutes).await?,
+            mailer: Arc::new(FileMailer::new(&config.storage.mail)?),
+            blobs: storage::open(&config)?,
+            oidc: Providers::new(config.oidc.clone()),
+            config: Arc::new(config),
+            pg: pool,
+        })
+    }
+    #[must_use]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+    /// Revocation sync, key reload and the purge of deleted accounts, expired uploads
+    /// and unused blobs.
+    pub fn spawn_background_jobs(&self) {
+        let sync = self.clone();
+        tokio::spawn(async move {
+            let mut every = tokio::time::interval(Duration::from_mins(1));
+            loop {
+                every.tick().await;
+                if let Err(e) = sync.sync_revocations().await {
+                    warn!("failed to sync revocation list: {e}");
+                }
+                if let Err(e) = sync.keys.reload() {
+                    warn!("failed to reload signing keys: {e}");
+                }
+            }
+        });
+        let purge = self.clone();
+        tokio::spawn(async move {
+            let mut every = tokio::time::interval(Duration::from_hours(1));
+            loop {
+                every.tick().await;
+                if let Err(e) = purge.purge_deleted_users().await {
+                    warn!("failed to purge deleted accounts: {e}");
+                }
+                if let Err(e) = purge.purge_expired_uploads().await {
+                    warn!("failed to purge expired uploads: {e}");
+                }
+                if let Err(e) = purge.purge_unused_blobs().await {
+                    warn!("failed to purge unused blobs: {e}");
+                }
+                if let Err(e) = purge.purge_login_throttle().await {
+                    warn!("failed to purge login failures: {e}");
+                }
+            }
+        });
+    }
+}
+
+/// The whole HTTP API.
+pub fn app(state: State) -> Router {
+    let body_limit = DefaultBodyLimit::max(state.config.uploads.max_bytes);
+    Router::new()
+        .route("/.well-known/jwks.json", get(jwks))
+        .merge(health_router())
+        .nest("/products", product_route(&state.config.uploads))
+        .nest("/auth", user_router())
+        .fallback(|| async { Error::NotFound })
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            audit_impersonation,
+        ))
+        .layer(body_limit)
+        .layer(middleware::from_fn(with_request_id))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .with_state(state)
+}
@@ -0,0 +1,45 @@
+use crate::error::Result;
+use chrono::Utc;
+use std::path::PathBuf;
+use tracing::info;
+
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> Result<()>;
+}
+
+/// Drops every mail as an `.eml` file in `dir` instead of delivering it.
+pub struct FileMailer {
+    dir: PathBuf,
+}
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+}
+
+#[async_trait::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> Result<()> {
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S"),
+            uuid::Uuid::new_v4()
+        ));
+        let content = format!(
+            "To: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            mail.to, mail.subject, mail.body
+        );
+        tokio::fs::write(&path, content).await?;
+        info!("mail \"{}\" written to {}", mail.subject, path.display());
+        Ok(())
+    }
+}
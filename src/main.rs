@@ -3,57 +3,83 @@
 #![deny(clippy::pedantic)]
 #![deny(clippy::nursery)]
 
-use axum::{
-    Router,
-    extract::Path,
-    response::{Html, IntoResponse},
-    routing::get,
-    serve,
-};
+use axum::serve;
+use clap::Parser;
+use devmarket::{Cli, Config, Error, Result, State, app, connect, migrate, pending_migrations};
 use dotenvy::dotenv;
-use sqlx::{PgPool, postgres::PgPoolOptions};
 use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tracing::info;
-#[cfg(test)]
-mod test;
-use crate::error::Result;
-use crate::{products::product_route, user::user_router};
-mod error;
-mod ext;
-mod products;
-mod user;
-#[derive(Clone)]
-struct State {
-    pg: PgPool,
-    jwt_secret: String,
-}
+use tokio::sync::watch;
+use tracing::{info, warn};
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    dotenv()?;
-    let url = std::env::var("DATABASE_URL")?;
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&url)
-        .await?;
-    let secret = std::env::var("jwt_secret")?;
-    let state = State {
-        pg: pool,
-        jwt_secret: secret,
-    };
+    dotenv().ok();
+    let cli = Cli::parse();
+    let config = Config::from_sources(&cli, std::env::vars())?;
+    let bind = config.server.bind;
+    let drain = Duration::from_secs(config.server.shutdown_timeout_secs);
     tracing_subscriber::fmt::init();
-    let router = Router::new()
-        .route("/:name", get(hello))
-        .nest("/products", product_route())
-        .nest("/auth", user_router())
-        .with_state(state);
+    let pool = connect(&config).await?;
+    if cli.migrate {
+        migrate(&pool).await?;
+        info!("migrations applied");
+    }
+    let pending = pending_migrations(&pool).await?;
+    if !pending.is_empty() {
+        return Err(Error::SchemaBehind(pending));
+    }
+    let state = State::new(config, pool).await?;
+    state.move_legacy_blobs().await?;
+    state.spawn_background_jobs();
 
-    let sock = SocketAddr::from(([127, 0, 0, 1], 8080));
-    info!("lisening on port {sock}");
-    let app = TcpListener::bind(sock).await?;
-    serve(app, router).await?;
+    info!("lisening on port {bind}");
+    let listener = TcpListener::bind(bind).await?;
+    let (stopping, mut stopped) = watch::channel(false);
+    let server = serve(
+        listener,
+        app(state).into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        info!("shutting down, draining requests for up to {drain:?}");
+        stopping.send_replace(true);
+    });
+    tokio::select! {
+        result = server => result?,
+        () = async {
+            let _ = stopped.wait_for(|stopping| *stopping).await;
+            tokio::time::sleep(drain).await;
+        } => warn!("drain timeout elapsed, dropping the remaining requests"),
+    }
     Ok(())
 }
-async fn hello(Path(name): Path<String>) -> impl IntoResponse {
-    Html(format!("hello {name}"))
+/// Resolves on Ctrl+C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("failed to listen for Ctrl+C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    // there is no SIGTERM elsewhere, Ctrl+C has to do
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = interrupt => {},
+        () = terminate => {},
+    }
 }
@@ -0,0 +1,232 @@
+use crate::State;
+use crate::error::{Error, Result};
+use crate::ext::IsAuth;
+use crate::user::Clains;
+use crate::user::api_key::Scope;
+use crate::user::model::Role;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+
+use std::marker::PhantomData;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// List products and change your own.
+    ProductWrite,
+    /// Edit or remove products of other users.
+    ProductModerate,
+    /// Edit or remove other accounts.
+    UserManage,
+    /// Sign other users out everywhere.
+    SessionManage,
+    LockoutManage,
+    SigningKeyManage,
+    MfaPolicyManage,
+    ApiKeyCreate,
+    /// Act as another, non-admin user for support.
+    Impersonate,
+    AuditRead,
+}
+
+impl Permission {
+    /// API key scope that grants the permission, `None` when keys can never use it.
+    pub const fn scope(self) -> Option<Scope> {
+        match self {
+            Self::ProductWrite | Self::ProductModerate => Some(Scope::ProductsWrite),
+            Self::UserManage
+            | Self::SessionManage
+            | Self::LockoutManage
+            | Self::SigningKeyManage
+            | Self::MfaPolicyManage
+            | Self::ApiKeyCreate
+            | Self::Impersonate
+            | Self::AuditRead => None,
+        }
+    }
+}
+
+pub const fn permissions(role: &Role) -> &'static [Permission] {
+    use Permission::{
+        ApiKeyCreate, AuditRead, Impersonate, LockoutManage, MfaPolicyManage, ProductModerate,
+        ProductWrite, SessionManage, SigningKeyManage, UserManage,
+    };
+    match role {
+        Role::Admin => &[
+            ProductWrite,
+            ProductModerate,
+            UserManage,
+            SessionManage,
+            LockoutManage,
+            SigningKeyManage,
+            MfaPolicyManage,
+            ApiKeyCreate,
+            Impersonate,
+            AuditRead,
+        ],
+        Role::Moderator => &[ProductModerate, SessionManage, LockoutManage],
+        Role::Seller => &[ProductWrite, ApiKeyCreate],
+        Role::Buyer => &[],
+    }
+}
+
+/// Whether the role grants `permission` and, for API keys, the key carries its scope.
+pub fn allows(claims: &Clains, permission: Permission) -> bool {
+    let scoped = match (&claims.scopes, permission.scope()) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(scopes), Some(scope)) => scopes.contains(&scope),
+    };
+    scoped && permissions(&claims.role).contains(&permission)
+}
+
+pub fn require(claims: &Clains, permission: Permission) -> Result<()> {
+    if allows(claims, permission) {
+        return Ok(());
+    }
+    if claims.scopes.is_some() && permissions(&claims.role).contains(&permission) {
+        return Err(Error::MissingScope);
+    }
+    Err(Error::InvalidUser)
+}
+
+/// Owners may change their own products, everyone else needs `ProductModerate`.
+pub fn require_product_owner(claims: &Clains, user_id: i32, owner_id: Option<i32>) -> Result<()> {
+    if owner_id == Some(user_id) {
+        return claims.require_scope(Scope::ProductsWrite);
+    }
+    require(claims, Permission::ProductModerate)
+}
+
+/// Users may change their own account, everyone else needs `UserManage` and a higher role.
+pub fn require_account_owner(claims: &Clains, username: &str, role: &Role) -> Result<()> {
+    if claims.username == username {
+        return Ok(());
+    }
+    require(claims, Permission::UserManage)?;
+    require_outranks(claims, role)
+}
+
+/// Admins outrank moderators, who outrank sellers and buyers.
+const fn rank(role: &Role) -> u8 {
+    match role {
+        Role::Admin => 2,
+        Role::Moderator => 1,
+        Role::Seller | Role::Buyer => 0,
+    }
+}
+
+/// Sessions and lockouts of a user may only be managed by a higher role.
+pub const fn require_outranks(claims: &Clains, target: &Role) -> Result<()> {
+    if rank(&claims.role) > rank(target) {
+        return Ok(());
+    }
+    Err(Error::InvalidUser)
+}
+
+pub trait Guard {
+    const PERMISSION: Permission;
+}
+
+macro_rules! guards {
+    ($($name:ident),* $(,)?) => {
+        $(
+            pub struct $name;
+            impl Guard for $name {
+                const PERMISSION: Permission = Permission::$name;
+            }
+        )*
+    };
+}
+guards!(
+    ProductWrite,
+    UserManage,
+    SessionManage,
+    LockoutManage,
+    SigningKeyManage,
+    MfaPolicyManage,
+    ApiKeyCreate,
+    Impersonate,
+    AuditRead,
+);
+
+/// Extractor that authenticates like `IsAuth` and then checks `G::PERMISSION`.
+pub struct Require<G>(pub Clains, pub PhantomData<G>);
+
+#[async_trait::async_trait]
+impl<G: Guard> FromRequestParts<State> for Require<G> {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &State) -> Result<Self> {
+        let IsAuth(claims) = IsAuth::from_request_parts(parts, state).await?;
+        require(&claims, G::PERMISSION)?;
+        Ok(Self(claims, PhantomData))
+    }
+}
+
+#[cfg(test)]
+fn claims(role: Role, scopes: Option<Vec<Scope>>) -> Clains {
+    let mut claims = Clains::new("policy".to_string(), role).unwrap();
+    claims.scopes = scopes;
+    claims
+}
+
+#[test]
+fn roles_t() {
+    let buyer = claims(Role::Buyer, None);
+    let seller = claims(Role::Seller, None);
+    let moderator = claims(Role::Moderator, None);
+    let admin = claims(Role::Admin, None);
+
+    assert!(!allows(&buyer, Permission::ProductWrite));
+    assert!(allows(&seller, Permission::ProductWrite));
+    assert!(!allows(&seller, Permission::ProductModerate));
+    assert!(allows(&moderator, Permission::ProductModerate));
+    assert!(!allows(&moderator, Permission::ProductWrite));
+    assert!(!allows(&moderator, Permission::UserManage));
+    assert!(!allows(&moderator, Permission::SigningKeyManage));
+    assert!(!allows(&moderator, Permission::Impersonate));
+    assert!(allows(&admin, Permission::Impersonate));
+    for permission in permissions(&Role::Moderator) {
+        assert!(allows(&admin, *permission));
+    }
+}
+
+#[test]
+fn ownership_t() {
+    let seller = claims(Role::Seller, None);
+    assert!(require_product_owner(&seller, 1, Some(1)).is_ok());
+    assert!(require_product_owner(&seller, 1, Some(2)).is_err());
+    assert!(require_product_owner(&claims(Role::Moderator, None), 1, Some(2)).is_ok());
+
+    let admin = claims(Role::Admin, None);
+    assert!(require_account_owner(&seller, "policy", &Role::Seller).is_ok());
+    assert!(require_account_owner(&admin, "policy", &Role::Admin).is_ok());
+    assert!(require_account_owner(&seller, "other", &Role::Buyer).is_err());
+    assert!(require_account_owner(&claims(Role::Moderator, None), "other", &Role::Buyer).is_err());
+    assert!(require_account_owner(&admin, "other", &Role::Seller).is_ok());
+    assert!(require_account_owner(&admin, "other", &Role::Admin).is_err());
+
+    let moderator = claims(Role::Moderator, None);
+    assert!(require_outranks(&moderator, &Role::Seller).is_ok());
+    assert!(require_outranks(&moderator, &Role::Moderator).is_err());
+    assert!(require_outranks(&moderator, &Role::Admin).is_err());
+    assert!(require_outranks(&admin, &Role::Moderator).is_ok());
+}
+
+#[test]
+fn scopes_t() {
+    let ci = claims(Role::Seller, Some(vec![Scope::ProductsWrite]));
+    assert!(allows(&ci, Permission::ProductWrite));
+    let read_only = claims(Role::Seller, Some(vec![Scope::ProductsRead]));
+    assert!(matches!(
+        require(&read_only, Permission::ProductWrite),
+        Err(Error::MissingScope)
+    ));
+    assert!(!allows(&ci, Permission::ApiKeyCreate));
+    assert!(require_product_owner(&ci, 1, Some(1)).is_ok());
+
+    let reader = claims(Role::Admin, Some(vec![Scope::ProductsRead]));
+    assert!(require_product_owner(&reader, 1, Some(1)).is_err());
+    assert!(require_product_owner(&reader, 1, Some(2)).is_err());
+    assert!(!allows(&reader, Permission::UserManage));
+}
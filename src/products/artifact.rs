@@ -0,0 +1,278 @@
+use crate::State;
+use crate::error::{Error, Result};
+use crate::products::format::{Arch, Inspection, Os, Platform};
+use crate::storage::Blob;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::query_as;
+use validator::{Validate, ValidationError};
+
+/// One build of a product, for `os` and, unless it runs on all of them, `arch`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Artifact {
+    pub id: i64,
+    pub product_id: i64,
+    pub os: String,
+    pub arch: Option<String>,
+    /// Sets apart builds for the same platform, e.g. `portable` or `musl`.
+    pub variant: Option<String>,
+    pub sha256: String,
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+}
+/// Which build a client asks for, `platform` wins over its `User-Agent`.
+#[derive(Deserialize, Validate)]
+pub struct ArtifactQuery {
+    pub platform: Option<String>,
+    #[validate(custom(function = "variant_name"))]
+    pub variant: Option<String>,
+}
+
+pub fn variant_name(variant: &str) -> std::result::Result<(), ValidationError> {
+    let valid = (1..=32).contains(&variant.len())
+        && variant
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_'));
+    if valid {
+        return Ok(());
+    }
+    Err(ValidationError::new("variant")
+        .with_message("must be 1 to 32 letters, digits, - or _".into()))
+}
+
+impl Artifact {
+    pub fn platform(&self) -> Result<Platform> {
+        let arch = self.arch.as_ref().map(|arch| format!("-{arch}"));
+        format!("{}{}", self.os, arch.unwrap_or_default()).parse()
+    }
+}
+
+/// What an upload is filed under: the declared platforms, the detected ones if none were
+/// declared. They have to agree on the OS, several architectures make it universal.
+pub fn artifact_platform(inspection: &Inspection, declared: &[Platform]) -> Result<Platform> {
+    let platforms = if declared.is_empty() {
+        &inspection.platforms
+    } else {
+        declared
+    };
+    let Some(first) = platforms.first() else {
+        return Err(Error::InvalidUpload("declare the platform of an archive"));
+    };
+    if platforms.iter().any(|platform| platform.os != first.os) {
+        return Err(Error::InvalidUpload("an artifact is built for a single OS"));
+    }
+    let arch = first.arch.filter(|arch| {
+        platforms
+            .iter()
+            .all(|platform| platform.arch == Some(*arch))
+    });
+    Ok(Platform::of(first.os, arch))
+}
+
+/// Best guess at the platform of a `User-Agent`, browsers and command line tools alike.
+pub fn user_agent_platform(user_agent: &str) -> Option<Platform> {
+    let agent = user_agent.to_lowercase();
+    let has = |tokens: &[&str]| tokens.iter().any(|token| agent.contains(token));
+    let os = if has(&["windows"]) {
+        Os::Windows
+    } else if has(&["mac os x", "macintosh", "macos", "darwin"]) {
+        Os::Macos
+    } else if has(&["linux"]) && !has(&["android"]) {
+        Os::Linux
+    } else {
+        return None;
+    };
+    let arch = if has(&["x86_64", "x64", "win64", "wow64", "amd64", "intel mac"]) {
+        Some(Arch::X86_64)
+    } else if has(&["aarch64", "arm64"]) {
+        Some(Arch::Arm64)
+    } else if has(&["i686", "i386", "x86"]) {
+        Some(Arch::X86)
+    } else if has(&["armv7"]) {
+        Some(Arch::Arm)
+    } else {
+        None
+    };
+    Some(Platform::of(os, arch))
+}
+
+/// How well a build for `build` suits a `client`, `None` if it doesn't run there.
+fn suitability(client: Platform, build: Platform) -> Option<u8> {
+    if client.os != build.os {
+        return None;
+    }
+    match (client.arch, build.arch) {
+        (Some(client), Some(build)) if client == build => Some(3),
+        (_, None) => Some(2),
+        // 32-bit builds run on 64-bit x86, Windows and macOS emulate x86_64 on arm64,
+        // and an unknown architecture is most likely x86_64
+        (Some(Arch::X86_64), Some(Arch::X86)) | (None, Some(Arch::X86_64)) => Some(1),
+        (Some(Arch::Arm64), Some(Arch::X86_64)) if client.os != Os::Linux => Some(1),
+        (None, Some(_)) => Some(0),
+        (Some(_), Some(_)) => None,
+    }
+}
+
+/// The artifact a client on `client` should get, builds without a variant unless one is
+/// asked for, the newest one among equals.
+pub fn best_match<'a>(
+    artifacts: &'a [Artifact],
+    client: Platform,
+    variant: Option<&str>,
+) -> Option<&'a Artifact> {
+    artifacts
+        .iter()
+        .filter(|artifact| {
+            variant.is_none_or(|variant| artifact.variant.as_deref() == Some(variant))
+        })
+        .filter_map(|artifact| {
+            let score = suitability(client, artifact.platform().ok()?)?;
+            Some((
+                (score, artifact.variant.is_none(), artifact.created_at),
+                artifact,
+            ))
+        })
+        .max_by_key(|(rank, _)| *rank)
+        .map(|(_, artifact)| artifact)
+}
+
+impl State {
+    /// Files `blob` under `platform` and `variant`, replacing the build that was there.
+    pub async fn add_artifact(
+        &self,
+        product_id: i64,
+        platform: Platform,
+        variant: Option<&str>,
+        blob: &Blob,
+    ) -> Result<Artifact> {
+        Ok(query_as!(
+            Artifact,
+            "INSERT INTO artifact (product_id, os, arch, variant, sha256, size)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (product_id, os, arch, variant)
+            DO UPDATE SET sha256 = $5, size = $6, created_at = now()
+            RETURNING id, product_id, os, arch, variant, sha256, size, created_at",
+            product_id,
+            platform.os.as_str(),
+            platform.arch.map(Arch::as_str),
+            variant,
+            blob.sha256,
+            blob.size,
+        )
+        .fetch_one(&self.pg)
+        .await?)
+    }
+    pub async fn artifacts_of(&self, product_id: i64) -> Result<Vec<Artifact>> {
+        Ok(query_as!(
+            Artifact,
+            "SELECT id, product_id, os, arch, variant, sha256, size, created_at FROM artifact
+            WHERE product_id = $1
+            ORDER BY os, arch, variant",
+            product_id,
+        )
+        .fetch_all(&self.pg)
+        .await?)
+    }
+    /// The artifacts of every product `owner_id` sells.
+    pub async fn artifacts_of_owner(&self, owner_id: i32) -> Result<Vec<Artifact>> {
+        Ok(query_as!(
+            Artifact,
+            "SELECT artifact.id, product_id, os, arch, variant, sha256, size, created_at
+            FROM artifact
+            JOIN Product ON Product.id = artifact.product_id
+            WHERE Product.owner_id = $1
+            ORDER BY artifact.id",
+            owner_id,
+        )
+        .fetch_all(&self.pg)
+        .await?)
+    }
+    pub async fn get_artifact(&self, product_id: i64, id: i64) -> Result<Artifact> {
+        Ok(query_as!(
+            Artifact,
+            "SELECT id, product_id, os, arch, variant, sha256, size, created_at FROM artifact
+            WHERE id = $1 AND product_id = $2",
+            id,
+            product_id,
+        )
+        .fetch_one(&self.pg)
+        .await?)
+    }
+    /// The blob stays until the purge finds nothing refers to it.
+    pub async fn delete_artifact(&self, product_id: i64, id: i64) -> Result<Artifact> {
+        Ok(query_as!(
+            Artifact,
+            "DELETE FROM artifact WHERE id = $1 AND product_id = $2
+            RETURNING id, product_id, os, arch, variant, sha256, size, created_at",
+            id,
+            product_id,
+        )
+        .fetch_one(&self.pg)
+        .await?)
+    }
+}
+
+#[test]
+fn best_match_t() {
+    let artifact = |id, os: &str, arch: Option<&str>, variant: Option<&str>| Artifact {
+        id,
+        product_id: 1,
+        os: os.to_string(),
+        arch: arch.map(str::to_string),
+        variant: variant.map(str::to_string),
+        sha256: String::new(),
+        size: 1,
+        created_at: DateTime::UNIX_EPOCH,
+    };
+    let artifacts = [
+        artifact(1, "windows", Some("x86_64"), None),
+        artifact(2, "windows", Some("x86"), None),
+        artifact(3, "windows", Some("x86_64"), Some("portable")),
+        artifact(4, "linux", Some("arm64"), None),
+        artifact(5, "macos", None, None),
+    ];
+    let best = |platform: &str, variant| {
+        best_match(&artifacts, platform.parse().unwrap(), variant).map(|artifact| artifact.id)
+    };
+    assert_eq!(best("windows-x86_64", None), Some(1));
+    assert_eq!(best("windows-x86_64", Some("portable")), Some(3));
+    assert_eq!(best("windows-x86", None), Some(2));
+    assert_eq!(best("windows-arm64", None), Some(1));
+    assert_eq!(best("windows", None), Some(1));
+    assert_eq!(best("linux-arm64", None), Some(4));
+    assert_eq!(best("linux-x86_64", None), None);
+    assert_eq!(best("macos-arm64", None), Some(5));
+    assert_eq!(best("macos-arm64", Some("portable")), None);
+
+    let firefox =
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:131.0) Gecko/20100101 Firefox/131.0";
+    assert_eq!(
+        user_agent_platform(firefox).map(|platform| platform.to_string()),
+        Some("windows-x86_64".to_string())
+    );
+    let cli = "devmarket-cli/1.2 (Linux aarch64)";
+    assert_eq!(
+        user_agent_platform(cli),
+        Some("linux-arm64".parse().unwrap())
+    );
+    let safari = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15";
+    assert_eq!(
+        user_agent_platform(safari),
+        Some("macos-x86_64".parse().unwrap())
+    );
+    assert_eq!(user_agent_platform("Mozilla/5.0 (Linux; Android 14)"), None);
+    assert_eq!(user_agent_platform("curl/8.5.0"), None);
+
+    let fat = crate::products::format::inspect(&[0xca, 0xfe, 0xba, 0xbe, 0, 0, 0, 1, 1, 0, 0, 7])
+        .map(|inspection| artifact_platform(&inspection, &[]).unwrap());
+    assert_eq!(fat, Some("macos-x86_64".parse().unwrap()));
+    let zip = crate::products::format::inspect(b"PK\x03\x04").unwrap();
+    assert!(artifact_platform(&zip, &[]).is_err());
+    let declared = crate::products::format::parse_platforms("macos-x86_64,macos-arm64").unwrap();
+    assert_eq!(
+        artifact_platform(&zip, &declared).unwrap(),
+        "macos".parse().unwrap()
+    );
+    let declared = crate::products::format::parse_platforms("macos,linux").unwrap();
+    assert!(artifact_platform(&zip, &declared).is_err());
+}
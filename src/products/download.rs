@@ -0,0 +1,227 @@
+use crate::State;
+use crate::error::Result;
+use crate::products::format::Format;
+use crate::products::model::Product;
+use crate::user::Clains;
+use crate::user::model::{Role, User};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{query, query_as, query_scalar};
+use std::ops::Range;
+
+#[derive(Debug, Serialize)]
+pub struct Download {
+    pub id: i64,
+    pub product_id: i64,
+    pub user_id: Option<i32>,
+    pub sha256: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The part of a `size` bytes file a `Range` header asks for. `None` means the whole file,
+/// which is also the answer to several ranges; `Some(Err(()))` can't be satisfied.
+pub fn byte_range(header: &str, size: u64) -> Option<std::result::Result<Range<u64>, ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            size.saturating_sub(suffix)..size
+        }
+        (start, "") => start.parse().ok()?..size,
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            start..end.saturating_add(1).min(size)
+        }
+    };
+    if range.start >= size || range.is_empty() {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
+/// Whether an `If-None-Match` list holds `etag`, weak validators compare equal.
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// `attachment` with a filename made of the product name, reduced to safe characters.
+pub fn content_disposition(product: &Product, format: Format) -> String {
+    let name: String = product
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("attachment; filename=\"{name}{}\"", format.extension())
+}
+
+impl State {
+    /// Free products are open to everyone; paid ones to their owner, admins and paying buyers.
+    pub async fn may_download(
+        &self,
+        product: &Product,
+        viewer: Option<(&Clains, &User)>,
+    ) -> Result<bool> {
+        if product.price.unwrap_or(0) == 0 {
+            return Ok(true);
+        }
+        let Some((claims, user)) = viewer else {
+            return Ok(false);
+        };
+        if claims.role == Role::Admin || product.owner_id == Some(user.id) {
+            return Ok(true);
+        }
+        self.has_purchased(product.id, user.id).await
+    }
+    pub async fn has_purchased(&self, product_id: i64, buyer_id: i32) -> Result<bool> {
+        Ok(query_scalar!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM purchase
+                WHERE product_id = $1 AND buyer_id = $2 AND paid_at IS NOT NULL
+            ) AS "purchased!""#,
+            product_id,
+            buyer_id,
+        )
+        .fetch_one(&self.pg)
+        .await?)
+    }
+    pub async fn record_download(&self, product: &Product, sha256: &str, user: Option<&User>) {
+        let recorded = query!(
+            "INSERT INTO download (product_id, user_id, sha256) VALUES ($1, $2, $3)",
+            product.id,
+            user.map(|user| user.id),
+            sha256,
+        )
+        .execute(&self.pg)
+        .await;
+        if let Err(e) = recorded {
+            tracing::warn!("failed to record download of product {}: {e}", product.id);
+        }
+    }
+    pub async fn download_count(&self, product_id: i64) -> Result<i64> {
+        Ok(query_scalar!(
+            r#"SELECT count(*) AS "count!" FROM download WHERE product_id = $1"#,
+            product_id
+        )
+        .fetch_one(&self.pg)
+        .await?)
+    }
+    pub async fn downloads_of(&self, user_id: i32) -> Result<Vec<Download>> {
+        Ok(query_as!(
+            Download,
+            "SELECT id, product_id, user_id, sha256, created_at FROM download
+            WHERE user_id = $1
+            ORDER BY id",
+            user_id,
+        )
+        .fetch_all(&self.pg)
+        .await?)
+    }
+}
+
+#[test]
+fn byte_range_t() {
+    assert_eq!(byte_range("bytes=0-9", 100), Some(Ok(0..10)));
+    assert_eq!(byte_range("bytes=90-", 100), Some(Ok(90..100)));
+    assert_eq!(byte_range("bytes=-10", 100), Some(Ok(90..100)));
+    assert_eq!(byte_range("bytes=-500", 100), Some(Ok(0..100)));
+    assert_eq!(byte_range("bytes=50-500", 100), Some(Ok(50..100)));
+    assert_eq!(byte_range("bytes=100-", 100), Some(Err(())));
+    assert_eq!(byte_range("bytes=-0", 100), Some(Err(())));
+    assert_eq!(byte_range("bytes=0-1,5-6", 100), None);
+    assert_eq!(byte_range("items=0-1", 100), None);
+    assert_eq!(byte_range("bytes=9-0", 100), None);
+
+    assert!(etag_matches("\"a\", W/\"b\"", "\"b\""));
+    assert!(etag_matches("*", "\"b\""));
+    assert!(!etag_matches("\"a\"", "\"b\""));
+}
+
+#[tokio::test]
+async fn download_t() {
+    use crate::products::model::NewProduct;
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let seller = state
+        .create_user(axum::Json(NewUser::test("dlseller")))
+        .await
+        .unwrap();
+    let buyer = state
+        .create_user(axum::Json(NewUser::test("dlbuyer")))
+        .await
+        .unwrap();
+    let product = |price| NewProduct {
+        name: "paid tool".to_string(),
+        description: "costs something".to_string(),
+        price,
+        owner_id: seller.id,
+        executable: Some(crate::products::format::test_executable(&[2])),
+    };
+    let paid = state.new_product(axum::Json(product(9))).await.unwrap();
+    let free = state.new_product(axum::Json(product(0))).await.unwrap();
+    let as_buyer = |user: &User| Clains::new(user.username.clone(), Role::Buyer).unwrap();
+    let (seller_claims, buyer_claims) = (as_buyer(&seller), as_buyer(&buyer));
+
+    assert!(state.may_download(&free, None).await.unwrap());
+    assert!(!state.may_download(&paid, None).await.unwrap());
+    let by_seller = Some((&seller_claims, &seller));
+    assert!(state.may_download(&paid, by_seller).await.unwrap());
+    let by_buyer = Some((&buyer_claims, &buyer));
+    assert!(!state.may_download(&paid, by_buyer).await.unwrap());
+    let moderator = Clains::new(buyer.username.clone(), Role::Moderator).unwrap();
+    assert!(
+        !state
+            .may_download(&paid, Some((&moderator, &buyer)))
+            .await
+            .unwrap()
+    );
+    let admin = Clains::new(buyer.username.clone(), Role::Admin).unwrap();
+    assert!(
+        state
+            .may_download(&paid, Some((&admin, &buyer)))
+            .await
+            .unwrap()
+    );
+    let purchase = state.purchase_product(paid.id, buyer.id).await.unwrap();
+    assert!(!state.may_download(&paid, by_buyer).await.unwrap());
+    let payment_ref = format!("dl-{}", purchase.id);
+    state
+        .confirm_payment(purchase.id, &payment_ref)
+        .await
+        .unwrap();
+    assert!(state.may_download(&paid, by_buyer).await.unwrap());
+    assert_eq!(
+        content_disposition(&paid, Format::Pe),
+        "attachment; filename=\"paid_tool.exe\""
+    );
+    assert_eq!(
+        content_disposition(&paid, Format::TarGz),
+        "attachment; filename=\"paid_tool.tar.gz\""
+    );
+
+    let sha256 = paid.executable_sha256.clone().unwrap();
+    state.record_download(&paid, &sha256, Some(&buyer)).await;
+    state.record_download(&paid, &sha256, None).await;
+    assert_eq!(state.download_count(paid.id).await.unwrap(), 2);
+    assert_eq!(state.downloads_of(buyer.id).await.unwrap().len(), 1);
+
+    state.delete_product(paid.id).await.unwrap();
+    state.delete_product(free.id).await.unwrap();
+    state.delete_user(seller.username).await.unwrap();
+    state.delete_user(buyer.username).await.unwrap();
+}
@@ -0,0 +1,484 @@
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+
+/// How much of a file `inspect` needs to see.
+pub const HEAD_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Pe,
+    Elf,
+    #[serde(rename = "macho")]
+    MachO,
+    #[serde(rename = "appimage")]
+    AppImage,
+    Deb,
+    Rpm,
+    Zip,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Os {
+    Windows,
+    Linux,
+    Macos,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Arch {
+    #[serde(rename = "x86")]
+    X86,
+    #[serde(rename = "x86_64")]
+    X86_64,
+    #[serde(rename = "arm")]
+    Arm,
+    #[serde(rename = "arm64")]
+    Arm64,
+}
+/// What a binary runs on, `arch` and `bits` are unknown for packages that don't say.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Platform {
+    pub os: Os,
+    pub arch: Option<Arch>,
+    pub bits: Option<u8>,
+}
+/// The result of `inspect`. Archives have no platforms, their content could be anything.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Inspection {
+    pub format: Format,
+    pub platforms: Vec<Platform>,
+}
+
+impl Format {
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Pe => "application/vnd.microsoft.portable-executable",
+            Self::Elf => "application/x-executable",
+            Self::MachO => "application/x-mach-binary",
+            Self::AppImage => "application/vnd.appimage",
+            Self::Deb => "application/vnd.debian.binary-package",
+            Self::Rpm => "application/x-rpm",
+            Self::Zip => "application/zip",
+            Self::TarGz => "application/gzip",
+        }
+    }
+    /// File name extension, empty for the binaries that usually have none.
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Pe => ".exe",
+            Self::Elf | Self::MachO => "",
+            Self::AppImage => ".AppImage",
+            Self::Deb => ".deb",
+            Self::Rpm => ".rpm",
+            Self::Zip => ".zip",
+            Self::TarGz => ".tar.gz",
+        }
+    }
+}
+
+impl Os {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Windows => "windows",
+            Self::Linux => "linux",
+            Self::Macos => "macos",
+        }
+    }
+}
+impl Arch {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::X86 => "x86",
+            Self::X86_64 => "x86_64",
+            Self::Arm => "arm",
+            Self::Arm64 => "arm64",
+        }
+    }
+    const fn bits(self) -> u8 {
+        match self {
+            Self::X86 | Self::Arm => 32,
+            Self::X86_64 | Self::Arm64 => 64,
+        }
+    }
+}
+
+/// `linux`, `windows-x86_64`, `macos-arm64`, ...
+impl FromStr for Platform {
+    type Err = Error;
+
+    fn from_str(platform: &str) -> Result<Self> {
+        let unknown = || Error::UnknownPlatform(platform.to_string());
+        let (os, arch) = match platform.trim().split_once('-') {
+            Some((os, arch)) => (os, Some(arch)),
+            None => (platform.trim(), None),
+        };
+        let os = match os.to_lowercase().as_str() {
+            "windows" => Os::Windows,
+            "linux" => Os::Linux,
+            "macos" | "darwin" => Os::Macos,
+            _ => return Err(unknown()),
+        };
+        let arch = match arch.map(str::to_lowercase).as_deref() {
+            None => None,
+            Some("x86" | "i386" | "i686") => Some(Arch::X86),
+            Some("x86_64" | "amd64" | "x64") => Some(Arch::X86_64),
+            Some("arm" | "armv7") => Some(Arch::Arm),
+            Some("arm64" | "aarch64") => Some(Arch::Arm64),
+            Some(_) => return Err(unknown()),
+        };
+        Ok(Self::of(os, arch))
+    }
+}
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let os = self.os.as_str();
+        match self.arch {
+            Some(arch) => write!(f, "{os}-{}", arch.as_str()),
+            None => f.write_str(os),
+        }
+    }
+}
+impl Platform {
+    /// The platform with the bitness `arch` implies.
+    pub fn of(os: Os, arch: Option<Arch>) -> Self {
+        Self {
+            os,
+            arch,
+            bits: arch.map(Arch::bits),
+        }
+    }
+    const fn new(os: Os, arch: Option<Arch>, bits: u8) -> Self {
+        Self {
+            os,
+            arch,
+            bits: Some(bits),
+        }
+    }
+    /// Same OS, and the same architecture unless one side doesn't know it.
+    fn covers(self, declared: Self) -> bool {
+        self.os == declared.os
+            && (self.arch.is_none() || declared.arch.is_none() || self.arch == declared.arch)
+    }
+}
+
+/// Comma separated platforms, as sellers declare them.
+pub fn parse_platforms(platforms: &str) -> Result<Vec<Platform>> {
+    platforms
+        .split(',')
+        .filter(|platform| !platform.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+impl Inspection {
+    /// Every declared platform has to be one the file was built for. Archives can't
+    /// be checked and take what they are given.
+    pub fn check(&self, declared: &[Platform]) -> Result<()> {
+        if self.platforms.is_empty() {
+            return Ok(());
+        }
+        let missing = declared
+            .iter()
+            .find(|declared| !self.platforms.iter().any(|found| found.covers(**declared)));
+        missing.map_or(Ok(()), |declared| {
+            Err(Error::PlatformMismatch {
+                declared: *declared,
+                detected: self.platforms.clone(),
+            })
+        })
+    }
+}
+
+fn u16_at(data: &[u8], at: usize, big_endian: bool) -> Option<u16> {
+    let bytes = data.get(at..at + 2)?.try_into().ok()?;
+    Some(if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    })
+}
+fn u32_at(data: &[u8], at: usize, big_endian: bool) -> Option<u32> {
+    let bytes = data.get(at..at + 4)?.try_into().ok()?;
+    Some(if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    })
+}
+
+/// Recognizes the supported formats from the first `HEAD_BYTES` of a file.
+pub fn inspect(head: &[u8]) -> Option<Inspection> {
+    pe(head)
+        .or_else(|| elf(head))
+        .or_else(|| mach_o(head))
+        .or_else(|| deb(head))
+        .or_else(|| rpm(head))
+        .or_else(|| zip(head))
+        .or_else(|| tar_gz(head))
+}
+
+/// A DOS header whose `e_lfanew` leads to a PE signature and optional header.
+fn pe(head: &[u8]) -> Option<Inspection> {
+    if !head.starts_with(b"MZ") {
+        return None;
+    }
+    let pe = usize::try_from(u32_at(head, 0x3c, false)?).ok()?;
+    if head.get(pe..pe + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let arch = match u16_at(head, pe + 4, false)? {
+        0x14c => Some(Arch::X86),
+        0x8664 => Some(Arch::X86_64),
+        0x1c0 | 0x1c4 => Some(Arch::Arm),
+        0xaa64 => Some(Arch::Arm64),
+        _ => None,
+    };
+    let bits = match u16_at(head, pe + 24, false)? {
+        0x10b => 32,
+        0x20b => 64,
+        _ => return None,
+    };
+    Some(Inspection {
+        format: Format::Pe,
+        platforms: vec![Platform::new(Os::Windows, arch, bits)],
+    })
+}
+
+/// Linux ELF binaries, `AppImages` carry their magic in the padding of the identification.
+fn elf(head: &[u8]) -> Option<Inspection> {
+    if !head.starts_with(b"\x7fELF") {
+        return None;
+    }
+    let bits = match head.get(4)? {
+        1 => 32,
+        2 => 64,
+        _ => return None,
+    };
+    let big_endian = match head.get(5)? {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    // System V or GNU/Linux, the BSDs and others aren't sold here
+    if !matches!(head.get(7)?, 0 | 3) {
+        return None;
+    }
+    let arch = match u16_at(head, 18, big_endian)? {
+        0x03 => Some(Arch::X86),
+        0x3e => Some(Arch::X86_64),
+        0x28 => Some(Arch::Arm),
+        0xb7 => Some(Arch::Arm64),
+        _ => None,
+    };
+    let format = if head.get(8..10) == Some(b"AI") && matches!(head.get(10), Some(1 | 2)) {
+        Format::AppImage
+    } else {
+        Format::Elf
+    };
+    Some(Inspection {
+        format,
+        platforms: vec![Platform::new(Os::Linux, arch, bits)],
+    })
+}
+
+const fn mach_o_platform(cpu_type: u32) -> Platform {
+    let arch = match cpu_type {
+        0x07 => Some(Arch::X86),
+        0x0100_0007 => Some(Arch::X86_64),
+        0x0c => Some(Arch::Arm),
+        0x0100_000c => Some(Arch::Arm64),
+        _ => None,
+    };
+    let bits = if cpu_type & 0x0100_0000 == 0 { 32 } else { 64 };
+    Platform::new(Os::Macos, arch, bits)
+}
+
+/// Thin Mach-O in either byte order, or a fat binary with one platform per slice.
+fn mach_o(head: &[u8]) -> Option<Inspection> {
+    let platforms = match u32_at(head, 0, true)? {
+        0xfeed_face | 0xfeed_facf => vec![mach_o_platform(u32_at(head, 4, true)?)],
+        0xcefa_edfe | 0xcffa_edfe => vec![mach_o_platform(u32_at(head, 4, false)?)],
+        magic @ (0xcafe_babe | 0xcafe_babf) => {
+            let slices = usize::try_from(u32_at(head, 4, true)?).ok()?;
+            // Java class files share the magic, their version makes for a large count
+            if slices == 0 || slices > 16 {
+                return None;
+            }
+            let entry = if magic == 0xcafe_babf { 32 } else { 20 };
+            (0..slices)
+                .map(|slice| u32_at(head, 8 + slice * entry, true).map(mach_o_platform))
+                .collect::<Option<Vec<_>>>()?
+        }
+        _ => return None,
+    };
+    Some(Inspection {
+        format: Format::MachO,
+        platforms,
+    })
+}
+
+/// An `ar` archive that starts with the `debian-binary` member.
+fn deb(head: &[u8]) -> Option<Inspection> {
+    if !head.starts_with(b"!<arch>\n") || !head.get(8..)?.starts_with(b"debian-binary") {
+        return None;
+    }
+    Some(Inspection {
+        format: Format::Deb,
+        platforms: vec![Platform::of(Os::Linux, None)],
+    })
+}
+
+/// The RPM lead, only a few architecture numbers are specific enough to report.
+fn rpm(head: &[u8]) -> Option<Inspection> {
+    if !head.starts_with(&[0xed, 0xab, 0xee, 0xdb]) || u16_at(head, 76, true)? != 1 {
+        return None;
+    }
+    let arch = match u16_at(head, 8, true)? {
+        12 => Some(Arch::Arm),
+        19 => Some(Arch::Arm64),
+        _ => None,
+    };
+    Some(Inspection {
+        format: Format::Rpm,
+        platforms: vec![Platform::of(Os::Linux, arch)],
+    })
+}
+
+fn zip(head: &[u8]) -> Option<Inspection> {
+    if !head.starts_with(b"PK\x03\x04") && !head.starts_with(b"PK\x05\x06") {
+        return None;
+    }
+    Some(Inspection {
+        format: Format::Zip,
+        platforms: Vec::new(),
+    })
+}
+
+/// Gzip whose content starts with a POSIX tar header.
+fn tar_gz(head: &[u8]) -> Option<Inspection> {
+    if !head.starts_with(&[0x1f, 0x8b, 0x08]) {
+        return None;
+    }
+    let mut header = [0; 262];
+    flate2::read::GzDecoder::new(head)
+        .read_exact(&mut header)
+        .ok()?;
+    if &header[257..262] != b"ustar" {
+        return None;
+    }
+    Some(Inspection {
+        format: Format::TarGz,
+        platforms: Vec::new(),
+    })
+}
+
+/// The smallest file `inspect` takes for a 64-bit Windows executable, `tag` makes it unique.
+#[cfg(test)]
+pub fn test_executable(tag: &[u8]) -> Vec<u8> {
+    let mut pe = vec![0; 0x5a];
+    pe[..2].copy_from_slice(b"MZ");
+    pe[0x3c] = 0x40;
+    pe[0x40..0x44].copy_from_slice(b"PE\0\0");
+    pe[0x44..0x46].copy_from_slice(&0x8664_u16.to_le_bytes());
+    pe[0x58..0x5a].copy_from_slice(&0x20b_u16.to_le_bytes());
+    pe.extend_from_slice(tag);
+    pe
+}
+
+#[test]
+fn format_t() {
+    use std::io::Write;
+    let platforms = |data: &[u8]| {
+        let inspection = inspect(data).unwrap();
+        let names: Vec<String> = inspection
+            .platforms
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        (inspection.format, names.join(","))
+    };
+    let pe = test_executable(b"");
+    assert_eq!(platforms(&pe), (Format::Pe, "windows-x86_64".to_string()));
+    assert_eq!(inspect(&pe).unwrap().platforms[0].bits, Some(64));
+    assert!(inspect(b"MZ but not a PE file").is_none());
+
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0];
+    elf.resize(20, 0);
+    elf[18] = 0xb7;
+    assert_eq!(platforms(&elf), (Format::Elf, "linux-arm64".to_string()));
+    elf[8..11].copy_from_slice(b"AI\x02");
+    assert_eq!(
+        platforms(&elf),
+        (Format::AppImage, "linux-arm64".to_string())
+    );
+    elf[7] = 9;
+    assert!(inspect(&elf).is_none());
+
+    let thin = [0xcf, 0xfa, 0xed, 0xfe, 0x0c, 0, 0, 0x01];
+    assert_eq!(platforms(&thin), (Format::MachO, "macos-arm64".to_string()));
+    let mut fat = vec![0xca, 0xfe, 0xba, 0xbe, 0, 0, 0, 2];
+    fat.extend(
+        [[0x01, 0, 0, 0x07], [0x01, 0, 0, 0x0c]]
+            .iter()
+            .flat_map(|cpu| {
+                let mut entry = cpu.to_vec();
+                entry.resize(20, 0);
+                entry
+            }),
+    );
+    assert_eq!(
+        platforms(&fat),
+        (Format::MachO, "macos-x86_64,macos-arm64".to_string())
+    );
+    assert!(inspect(&[0xca, 0xfe, 0xba, 0xbe, 0, 0, 0, 0x34]).is_none());
+
+    assert_eq!(
+        platforms(b"!<arch>\ndebian-binary   "),
+        (Format::Deb, "linux".to_string())
+    );
+    let mut rpm = vec![0xed, 0xab, 0xee, 0xdb, 3, 0, 0, 0, 0, 19];
+    rpm.resize(78, 0);
+    rpm[77] = 1;
+    assert_eq!(platforms(&rpm), (Format::Rpm, "linux-arm64".to_string()));
+    assert_eq!(platforms(b"PK\x03\x04rest"), (Format::Zip, String::new()));
+
+    let mut tar = vec![0; 512];
+    tar[257..262].copy_from_slice(b"ustar");
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&tar).unwrap();
+    assert_eq!(
+        platforms(&gz.finish().unwrap()),
+        (Format::TarGz, String::new())
+    );
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&[0; 512]).unwrap();
+    assert!(inspect(&gz.finish().unwrap()).is_none());
+}
+
+#[test]
+fn platform_t() {
+    let declared = parse_platforms("windows-x86_64, macos").unwrap();
+    assert_eq!(declared[0].bits, Some(64));
+    assert_eq!(declared[1].to_string(), "macos");
+    assert!(parse_platforms("windows-sparc").is_err());
+    assert!(parse_platforms("haiku").is_err());
+
+    let pe = inspect(&test_executable(b"")).unwrap();
+    assert!(pe.check(&declared[..1]).is_ok());
+    assert!(pe.check(&parse_platforms("windows").unwrap()).is_ok());
+    assert!(matches!(
+        pe.check(&parse_platforms("linux-x86_64").unwrap()),
+        Err(Error::PlatformMismatch { .. })
+    ));
+    assert!(
+        pe.check(&parse_platforms("windows-arm64").unwrap())
+            .is_err()
+    );
+    let zip = inspect(b"PK\x03\x04").unwrap();
+    assert!(zip.check(&declared).is_ok());
+}
@@ -1,83 +1,151 @@
+use crate::audit::Audit;
+use crate::config::UploadConfig;
 use crate::error::{Error, Result};
-use crate::ext::IsAuth;
-use crate::user::model::Role;
+use crate::ext::{IsAuth, Path, Query, ValidatedJson};
+use crate::policy::{ProductWrite, Require, require_product_owner};
+use crate::user::{Clains, model::User};
 use crate::{
     State as Mc,
+    products::artifact::{Artifact, ArtifactQuery, best_match, user_agent_platform, variant_name},
+    products::download::{byte_range, content_disposition, etag_matches},
+    products::format::{Format, Platform, parse_platforms},
     products::model::{NewProduct, Product, UpdateProduct},
+    products::upload::{NewUpload, Upload, UploadProgress},
 };
 use axum::{
     Json, Router,
-    extract::{Path, Query, State},
+    body::Body,
+    extract::{DefaultBodyLimit, Multipart, State},
+    http::{
+        HeaderMap, Method, StatusCode,
+        header::{
+            ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE,
+            CONTENT_TYPE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE, USER_AGENT,
+        },
+    },
+    response::{IntoResponse, Response},
     routing::{delete, get, post, put},
 };
+use futures_util::StreamExt;
 use serde::Deserialize;
 use serde_json::Value;
 use tracing::info;
+use uuid::Uuid;
+use validator::Validate;
 #[derive(Deserialize)]
 struct Qer {
     page: Option<i32>,
 }
-mod model;
-pub fn product_route() -> Router<Mc> {
+pub mod artifact;
+pub mod download;
+pub mod format;
+pub mod model;
+pub mod purchase;
+pub mod upload;
+/// Room for the multipart boundaries and headers around an artifact.
+const MULTIPART_OVERHEAD: usize = 64 * 1024;
+pub fn product_route(uploads: &UploadConfig) -> Router<Mc> {
+    let artifact_limit = usize::try_from(uploads.artifact_max_bytes)
+        .unwrap_or(usize::MAX)
+        .saturating_add(MULTIPART_OVERHEAD);
     Router::new()
         .route("/", post(new_product))
         .route("/", get(all_product))
         .route("/:id", delete(delete_product))
         .route("/:id", put(update_product))
         .route("/:id", get(get_product))
+        .route("/:id/download", get(download_product))
+        .route(
+            "/:id/artifacts",
+            get(list_artifacts)
+                .post(upload_artifact)
+                .layer(DefaultBodyLimit::max(artifact_limit)),
+        )
+        .route("/:id/artifacts/best", get(best_artifact))
+        .route("/:id/artifacts/:artifact", delete(delete_artifact))
+        .route("/:id/artifacts/:artifact/download", get(download_artifact))
+        .route("/:id/artifacts/uploads", post(create_upload))
+        .route(
+            "/:id/artifacts/uploads/:upload",
+            get(get_upload).patch(append_upload).delete(cancel_upload),
+        )
 }
 async fn new_product(
-    IsAuth(ext): IsAuth,
+    Require(ext, _): Require<ProductWrite>,
+    audit: Audit,
     State(mc): State<Mc>,
-    data: Json<NewProduct>,
+    ValidatedJson(data): ValidatedJson<NewProduct>,
 ) -> Result<Json<Product>> {
-    if (ext.role == Role::Seller) || (ext.role == Role::Admin) {
-        info!("starting new product");
-        let data = mc.new_product(data).await?;
-        info!("new product inserted");
-        return Ok(Json(data));
-    }
-    Err(Error::InvalidUser)
+    let user = mc.get_user(ext.username.clone()).await?;
+    user.ensure_verified()?;
+    require_product_owner(&ext, user.id, Some(data.owner_id))?;
+    info!("starting new product");
+    let data = mc.new_product(Json(data)).await?;
+    mc.audit(
+        &audit.by(&ext),
+        "product.create",
+        "product",
+        data.id,
+        None,
+        Some(&data),
+    )
+    .await;
+    info!("new product inserted");
+    Ok(Json(data))
 }
-#[axum::debug_handler]
 async fn all_product(State(mc): State<Mc>, Query(meta): Query<Qer>) -> Result<Json<Vec<Product>>> {
-    let max = meta.page.unwrap_or(1) * 10;
     info!("starting to fetch all products");
-    let data = mc.all_product(max).await?;
+    let data = mc.all_product(meta.page.unwrap_or(1)).await?;
     info!("all data has been fetched");
     Ok(Json(data))
 }
 async fn delete_product(
     IsAuth(ext): IsAuth,
+    audit: Audit,
     State(mc): State<Mc>,
     Path(id): Path<i64>,
 ) -> Result<Json<Product>> {
+    ext.forbid_impersonation()?;
     let product = mc.get_product(id).await?;
-    let owner = mc.get_user(ext.username).await?;
-    if product.owner_id == owner.id || ext.role == Role::Admin {
-        info!("starting to delete product");
-        let data = mc.delete_product(id).await?;
-        info!("product has been deleted");
-        return Ok(Json(data));
-    }
-    Err(Error::InvalidUser)
+    let user = mc.get_user(ext.username.clone()).await?;
+    require_product_owner(&ext, user.id, product.owner_id)?;
+    info!("starting to delete product");
+    let data = mc.delete_product(id).await?;
+    mc.audit(
+        &audit.by(&ext),
+        "product.delete",
+        "product",
+        id,
+        Some(&data),
+        None,
+    )
+    .await;
+    info!("product has been deleted");
+    Ok(Json(data))
 }
-#[axum::debug_handler]
 async fn update_product(
     IsAuth(ext): IsAuth,
+    audit: Audit,
     State(mc): State<Mc>,
     Path(id): Path<i64>,
-    data: Json<UpdateProduct>,
+    ValidatedJson(data): ValidatedJson<UpdateProduct>,
 ) -> Result<Json<Product>> {
     let product = mc.get_product(id).await?;
-    let owner = mc.get_user(ext.username).await?;
-    if product.owner_id == owner.id || ext.role == Role::Admin {
-        info!("updating started");
-        let pool = mc.update_product(id, data).await?;
-        info!("finished updating product");
-        return Ok(Json(pool));
-    }
-    Err(Error::InvalidUser)
+    let user = mc.get_user(ext.username.clone()).await?;
+    require_product_owner(&ext, user.id, product.owner_id)?;
+    info!("updating started");
+    let pool = mc.update_product(id, Json(data)).await?;
+    mc.audit(
+        &audit.by(&ext),
+        "product.update",
+        "product",
+        id,
+        Some(&product),
+        Some(&pool),
+    )
+    .await;
+    info!("finished updating product");
+    Ok(Json(pool))
 }
 async fn get_product(State(mc): State<Mc>, Path(id): Path<i64>) -> Result<Json<Value>> {
     info!("fetching product started");
@@ -85,3 +153,287 @@ async fn get_product(State(mc): State<Mc>, Path(id): Path<i64>) -> Result<Json<V
     info!("fetching product went ");
     Ok(pool)
 }
+/// The platform a client asked for, or the one its `User-Agent` suggests.
+fn client_platform(query: &ArtifactQuery, headers: &HeaderMap) -> Result<Option<Platform>> {
+    if let Some(platform) = &query.platform {
+        return platform.parse().map(Some);
+    }
+    Ok(headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(user_agent_platform))
+}
+/// Sends the build that suits the client best, the product's own executable if none does.
+async fn download_product(
+    auth: Option<IsAuth>,
+    State(mc): State<Mc>,
+    Path(id): Path<i64>,
+    Query(query): Query<ArtifactQuery>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response> {
+    query.validate()?;
+    let product = mc.get_product(id).await?;
+    let artifacts = mc.artifacts_of(id).await?;
+    let best = client_platform(&query, &headers)?
+        .and_then(|client| best_match(&artifacts, client, query.variant.as_deref()));
+    let (sha256, size) = match (best, &product.executable_sha256, product.executable_size) {
+        (Some(artifact), _, _) => (artifact.sha256.clone(), artifact.size),
+        (None, Some(sha256), Some(size)) => (sha256.clone(), size),
+        (None, ..) if artifacts.is_empty() => return Err(Error::NotFound),
+        (None, ..) => return Err(Error::NoArtifact),
+    };
+    send_blob(&mc, auth, &product, &sha256, size, &method, &headers).await
+}
+async fn download_artifact(
+    auth: Option<IsAuth>,
+    State(mc): State<Mc>,
+    Path((id, artifact)): Path<(i64, i64)>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let product = mc.get_product(id).await?;
+    let artifact = mc.get_artifact(id, artifact).await?;
+    send_blob(
+        &mc,
+        auth,
+        &product,
+        &artifact.sha256,
+        artifact.size,
+        &method,
+        &headers,
+    )
+    .await
+}
+/// Streams a blob of `product`, a single `Range` gets a 206 and a matching `If-None-Match` a 304.
+async fn send_blob(
+    mc: &Mc,
+    auth: Option<IsAuth>,
+    product: &Product,
+    sha256: &str,
+    size: i64,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    let user = match &auth {
+        Some(IsAuth(claims)) => Some(mc.get_user(claims.username.clone()).await?),
+        None => None,
+    };
+    let viewer = auth
+        .as_ref()
+        .map(|IsAuth(claims)| claims)
+        .zip(user.as_ref());
+    if !mc.may_download(product, viewer).await? {
+        return Err(if viewer.is_some() {
+            Error::NotPurchased
+        } else {
+            Error::NotLoggedIn
+        });
+    }
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let etag = format!("\"{sha256}\"");
+    if header(IF_NONE_MATCH).is_some_and(|tags| etag_matches(tags, &etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+    }
+    let size = u64::try_from(size)?;
+    // a range of another version than the one the client has is no use to it
+    let range = match header(RANGE) {
+        Some(range) if header(IF_RANGE).is_none_or(|tag| tag == etag) => byte_range(range, size),
+        _ => None,
+    }
+    .transpose()
+    .map_err(|()| Error::RangeNotSatisfiable(size))?;
+    // executables from before inspection existed were all Windows ones
+    let format = mc
+        .blob_inspection(sha256)
+        .await?
+        .map_or(Format::Pe, |inspection| inspection.format);
+    let mut headers = vec![
+        (CONTENT_TYPE, format.content_type().to_string()),
+        (CONTENT_DISPOSITION, content_disposition(product, format)),
+        (ETAG, etag),
+        (ACCEPT_RANGES, "bytes".to_string()),
+        (CACHE_CONTROL, "private".to_string()),
+    ];
+    let status = if let Some(range) = &range {
+        let content_range = format!("bytes {}-{}/{size}", range.start, range.end - 1);
+        headers.push((CONTENT_RANGE, content_range));
+        headers.push((CONTENT_LENGTH, (range.end - range.start).to_string()));
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        headers.push((CONTENT_LENGTH, size.to_string()));
+        StatusCode::OK
+    };
+    let headers: HeaderMap = headers
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.try_into().ok()?)))
+        .collect();
+    if method == Method::HEAD {
+        return Ok((status, headers).into_response());
+    }
+    let body = mc.blobs.read(sha256, range.clone()).await?;
+    // resumed downloads only count once, when they start at the beginning
+    if range.is_none_or(|range| range.start == 0) {
+        mc.record_download(product, sha256, user.as_ref()).await;
+    }
+    info!("download of product {} started", product.id);
+    Ok((status, headers, Body::from_stream(body)).into_response())
+}
+async fn list_artifacts(State(mc): State<Mc>, Path(id): Path<i64>) -> Result<Json<Vec<Artifact>>> {
+    mc.get_product(id).await?;
+    Ok(Json(mc.artifacts_of(id).await?))
+}
+/// The artifact `/download` sends the same client.
+async fn best_artifact(
+    State(mc): State<Mc>,
+    Path(id): Path<i64>,
+    Query(query): Query<ArtifactQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Artifact>> {
+    query.validate()?;
+    mc.get_product(id).await?;
+    let client = client_platform(&query, &headers)?.ok_or(Error::NoArtifact)?;
+    let artifacts = mc.artifacts_of(id).await?;
+    best_match(&artifacts, client, query.variant.as_deref())
+        .cloned()
+        .map(Json)
+        .ok_or(Error::NoArtifact)
+}
+/// The product, if the caller may replace its executable.
+async fn writable_product(mc: &Mc, ext: &Clains, id: i64) -> Result<(User, Product)> {
+    let user = mc.get_user(ext.username.clone()).await?;
+    user.ensure_verified()?;
+    let product = mc.get_product(id).await?;
+    require_product_owner(ext, user.id, product.owner_id)?;
+    Ok((user, product))
+}
+#[derive(Deserialize, Validate)]
+struct UploadQuery {
+    /// Comma separated, e.g. `windows-x86_64,windows-arm64`. Detected when left out.
+    platforms: Option<String>,
+    #[validate(custom(function = "variant_name"))]
+    variant: Option<String>,
+}
+/// Takes a build from the `file` field of a multipart body, in one go.
+async fn upload_artifact(
+    Require(ext, _): Require<ProductWrite>,
+    audit: Audit,
+    State(mc): State<Mc>,
+    Path(id): Path<i64>,
+    Query(query): Query<UploadQuery>,
+    mut multipart: Multipart,
+) -> Result<Json<Artifact>> {
+    query.validate()?;
+    writable_product(&mc, &ext, id).await?;
+    let platforms = parse_platforms(query.platforms.as_deref().unwrap_or_default())?;
+    while let Some(field) = multipart.next_field().await? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        info!("artifact upload started");
+        let artifact = mc
+            .upload_artifact(
+                id,
+                field.map(|chunk| Ok(chunk?)),
+                &platforms,
+                query.variant.as_deref(),
+            )
+            .await?;
+        mc.audit(
+            &audit.by(&ext),
+            "product.artifact",
+            "product",
+            id,
+            None,
+            Some(&artifact),
+        )
+        .await;
+        info!("artifact {} uploaded", artifact.id);
+        return Ok(Json(artifact));
+    }
+    Err(Error::InvalidUpload("expected a `file` field"))
+}
+async fn delete_artifact(
+    Require(ext, _): Require<ProductWrite>,
+    audit: Audit,
+    State(mc): State<Mc>,
+    Path((id, artifact)): Path<(i64, i64)>,
+) -> Result<Json<Artifact>> {
+    writable_product(&mc, &ext, id).await?;
+    let artifact = mc.delete_artifact(id, artifact).await?;
+    mc.audit(
+        &audit.by(&ext),
+        "product.artifact.delete",
+        "product",
+        id,
+        Some(&artifact),
+        None,
+    )
+    .await;
+    info!("artifact {} deleted", artifact.id);
+    Ok(Json(artifact))
+}
+async fn create_upload(
+    Require(ext, _): Require<ProductWrite>,
+    State(mc): State<Mc>,
+    Path(id): Path<i64>,
+    ValidatedJson(data): ValidatedJson<NewUpload>,
+) -> Result<Json<Upload>> {
+    let (user, _) = writable_product(&mc, &ext, id).await?;
+    let upload = mc.create_upload(id, user.id, data).await?;
+    info!("resumable upload {} started", upload.id);
+    Ok(Json(upload))
+}
+/// Where to resume after a failed chunk.
+async fn get_upload(
+    Require(ext, _): Require<ProductWrite>,
+    State(mc): State<Mc>,
+    Path((id, upload)): Path<(i64, Uuid)>,
+) -> Result<Json<Upload>> {
+    let (user, _) = writable_product(&mc, &ext, id).await?;
+    Ok(Json(mc.get_upload(id, upload, user.id).await?))
+}
+/// The raw body is the chunk starting at the `Upload-Offset` header.
+async fn append_upload(
+    Require(ext, _): Require<ProductWrite>,
+    audit: Audit,
+    State(mc): State<Mc>,
+    Path((id, upload)): Path<(i64, Uuid)>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<UploadProgress>> {
+    let offset = headers
+        .get("upload-offset")
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .ok_or(Error::InvalidUpload("expected an Upload-Offset header"))?;
+    let (user, _) = writable_product(&mc, &ext, id).await?;
+    let upload = mc.get_upload(id, upload, user.id).await?;
+    let chunks = body
+        .into_data_stream()
+        .map(|chunk| chunk.map_err(|_| Error::InvalidUpload("body interrupted")));
+    let progress = mc.append_upload(upload, offset, chunks).await?;
+    if let Some(artifact) = &progress.artifact {
+        mc.audit(
+            &audit.by(&ext),
+            "product.artifact",
+            "product",
+            id,
+            None,
+            Some(artifact),
+        )
+        .await;
+        info!("resumable upload {} finished", progress.upload.id);
+    }
+    Ok(Json(progress))
+}
+async fn cancel_upload(
+    Require(ext, _): Require<ProductWrite>,
+    State(mc): State<Mc>,
+    Path((id, upload)): Path<(i64, Uuid)>,
+) -> Result<Json<Upload>> {
+    let (user, _) = writable_product(&mc, &ext, id).await?;
+    let upload = mc.get_upload(id, upload, user.id).await?;
+    mc.cancel_upload(&upload).await?;
+    info!("resumable upload {} cancelled", upload.id);
+    Ok(Json(upload))
+}
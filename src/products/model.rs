@@ -1,13 +1,20 @@
 use crate::error::Result;
+use crate::products::format::inspect;
 use crate::{State, error::Error};
 use axum::Json;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::{FromRow, query_as};
-#[derive(Debug, Deserialize)]
+use validator::Validate;
+/// Products per page of the listing.
+const PAGE_SIZE: i64 = 10;
+#[derive(Debug, Deserialize, Validate)]
 pub struct NewProduct {
+    #[validate(length(min = 1, max = 40, message = "must be 1 to 40 characters"))]
     pub name: String,
     pub description: String,
+    #[validate(range(min = 0, message = "can't be negative"))]
     pub price: i32,
     pub owner_id: i32,
     pub executable: Option<Vec<u8>>,
@@ -20,52 +27,63 @@ pub struct Product {
     pub description: String,
     pub price: Option<i32>,
     pub rating: Option<i16>,
-    pub owner_id: i32,
-    pub executable: Option<Vec<u8>>,
+    pub owner_id: Option<i32>,
+    /// Key of the executable in the blob store.
+    pub executable_sha256: Option<String>,
+    pub executable_size: Option<i64>,
+    /// Set while the product is hidden from the catalogue, e.g. after its seller left.
+    pub unlisted_at: Option<DateTime<Utc>>,
 }
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct UpdateProduct {
+    #[validate(length(min = 1, max = 40, message = "must be 1 to 40 characters"))]
     pub name: String,
     pub description: String,
+    #[validate(range(min = 0, message = "can't be negative"))]
     pub price: i32,
     pub executable: Option<Vec<u8>>,
 }
-fn is_exe_file(data: &[u8]) -> bool {
-    data.starts_with(&[0x4D, 0x5A])
-}
 
 impl State {
     pub async fn new_product(&self, data: Json<NewProduct>) -> Result<Product> {
-        if let Some(file) = &data.executable {
-            if !is_exe_file(file) {
-                return Err(Error::Datatype);
+        let Json(data) = data;
+        let blob = match data.executable {
+            Some(file) => {
+                let inspection = inspect(&file).ok_or(Error::Datatype)?;
+                Some(self.put_blob(file, &inspection).await?)
             }
-        }
+            None => None,
+        };
 
         let store = query_as!(
             Product,
             r#"
-            INSERT INTO Product (name, description, price, owner_id, executable)
-            VALUES ($1, $2, $3, $4, $5)
-            RETURNING id, name, description, price, rating, owner_id, executable
+            INSERT INTO Product (name, description, price, owner_id, executable_sha256, executable_size)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, name, description, price, rating, owner_id, executable_sha256, executable_size, unlisted_at
             "#,
             data.name,
             data.description,
             data.price,
             data.owner_id,
-            data.executable,
+            blob.as_ref().map(|blob| blob.sha256.clone()),
+            blob.as_ref().map(|blob| blob.size),
         )
         .fetch_one(&self.pg)
         .await?;
 
         Ok(store)
     }
-    pub async fn all_product(&self, max: i32) -> Result<Vec<Product>> {
-        let store = query_as::<_, Product>("SELECT * FROM Product OFFSET $1 LIMIT $2")
-            .bind(max)
-            .bind(max)
-            .fetch_all(&self.pg)
-            .await?;
+    /// Listed products, `PAGE_SIZE` at a time; pages start at 1.
+    pub async fn all_product(&self, page: i32) -> Result<Vec<Product>> {
+        let offset = (i64::from(page.max(1)) - 1) * PAGE_SIZE;
+        let store = query_as::<_, Product>(
+            "SELECT * FROM Product WHERE unlisted_at IS NULL ORDER BY id OFFSET $1 LIMIT $2",
+        )
+        .bind(offset)
+        .bind(PAGE_SIZE)
+        .fetch_all(&self.pg)
+        .await?;
         Ok(store)
     }
     pub async fn delete_product(&self, id: i64) -> Result<Product> {
@@ -73,7 +91,7 @@ impl State {
             Product,
             "DELETE FROM Product 
             WHERE id = $1
-            RETURNING id, name, description, price, rating, owner_id, executable
+            RETURNING id, name, description, price, rating, owner_id, executable_sha256, executable_size, unlisted_at
             ",
             id
         )
@@ -82,21 +100,25 @@ impl State {
         Ok(store)
     }
     pub async fn update_product(&self, id: i64, data: Json<UpdateProduct>) -> Result<Product> {
-        if let Some(file) = &data.executable {
-            if !is_exe_file(file) {
-                return Err(Error::Datatype);
+        let Json(data) = data;
+        let blob = match data.executable {
+            Some(file) => {
+                let inspection = inspect(&file).ok_or(Error::Datatype)?;
+                Some(self.put_blob(file, &inspection).await?)
             }
-        }
+            None => None,
+        };
         let store = query_as!(
             Product,
             "UPDATE Product
-            SET name=$1, description=$2, price=$3, executable=$4
-            WHERE id = $5
-            RETURNING id, name, description, price, rating, owner_id, executable",
+            SET name=$1, description=$2, price=$3, executable_sha256=$4, executable_size=$5
+            WHERE id = $6
+            RETURNING id, name, description, price, rating, owner_id, executable_sha256, executable_size, unlisted_at",
             data.name,
             data.description,
             data.price,
-            data.executable,
+            blob.as_ref().map(|blob| blob.sha256.clone()),
+            blob.as_ref().map(|blob| blob.size),
             id,
         )
         .fetch_one(&self.pg)
@@ -106,7 +128,7 @@ impl State {
     pub async fn get_product(&self, id: i64) -> Result<Product> {
         let store = query_as!(
             Product,
-            "SELECT id, name, description, price, rating, owner_id, executable FROM Product
+            "SELECT id, name, description, price, rating, owner_id, executable_sha256, executable_size, unlisted_at FROM Product
             WHERE id = $1",
             id
         )
@@ -114,6 +136,18 @@ impl State {
         .await?;
         Ok(store)
     }
+    pub async fn products_of(&self, owner_id: i32) -> Result<Vec<Product>> {
+        let store = query_as!(
+            Product,
+            "SELECT id, name, description, price, rating, owner_id, executable_sha256, executable_size, unlisted_at FROM Product
+            WHERE owner_id = $1
+            ORDER BY id",
+            owner_id
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        Ok(store)
+    }
     pub async fn get_full_product(&self, id: i64) -> Result<Json<Value>> {
         let row: (Value,) = sqlx::query_as(
             r#"
@@ -123,9 +157,24 @@ impl State {
                     Product.name, 
                     Product.price, 
                     Product.rating, 
+                    Product.executable_size,
+                    blob.inspection->'format' AS format,
+                    COALESCE(blob.inspection->'platforms', '[]') AS platforms,
+                    (
+                        SELECT COALESCE(jsonb_agg(jsonb_build_object(
+                            'id', artifact.id,
+                            'os', artifact.os,
+                            'arch', artifact.arch,
+                            'variant', artifact.variant,
+                            'size', artifact.size
+                        ) ORDER BY artifact.os, artifact.arch, artifact.variant), '[]')
+                        FROM artifact WHERE artifact.product_id = Product.id
+                    ) AS artifacts,
+                    (SELECT count(*) FROM download WHERE download.product_id = Product.id) AS downloads,
                     "User".username 
                 FROM Product
                 LEFT JOIN "User" ON Product.owner_id = "User".id
+                LEFT JOIN blob ON blob.sha256 = Product.executable_sha256
                 WHERE Product.id = $1
             ) AS result;
             "#,
@@ -139,37 +188,38 @@ impl State {
 }
 #[tokio::test]
 async fn tt_all() {
-    dotenvy::dotenv().ok();
-    let url = std::env::var("DATABASE_URL").unwrap();
-    let pool = sqlx::postgres::PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&url)
+    let state = crate::test::state().await;
+    let owner = state
+        .create_user(Json(crate::user::model::NewUser::test("ttall")))
         .await
         .unwrap();
-    let sec = std::env::var("jwt_secret").unwrap();
-    let state = State {
-        pg: pool,
-        jwt_secret: sec,
-    };
     println!("{:?}", state.all_product(2).await);
     let data: Json<NewProduct> = Json(NewProduct {
         name: "work space".to_string(),
         description: "it is a works space app".to_string(),
         price: 70,
-        owner_id: 2,
-        executable: std::option::Option::Some(vec![7]),
+        owner_id: owner.id,
+        executable: std::option::Option::Some(crate::products::format::test_executable(&[7])),
     });
     let new = state.new_product(data).await.unwrap();
     println!("{new:?}");
-    println!("{:?}", state.all_product(2).await);
+    let first = state.all_product(1).await.unwrap();
+    let second = state.all_product(2).await.unwrap();
+    assert!(!first.is_empty() && first.len() <= 10);
+    assert!(
+        second
+            .iter()
+            .all(|later| first.iter().all(|p| p.id < later.id))
+    );
     let up = Json(UpdateProduct {
         name: "amine".to_string(),
         description: "test description".to_string(),
         price: 77,
-        executable: std::option::Option::Some(vec![7]),
+        executable: std::option::Option::Some(crate::products::format::test_executable(&[7])),
     });
     println!("{:?}", state.update_product(new.id, up).await);
     println!("{:?}", state.all_product(2).await);
     println!("{:?}", state.delete_product(new.id).await);
     println!("{:?}", state.all_product(2).await);
+    state.delete_user(owner.username).await.unwrap();
 }
@@ -0,0 +1,59 @@
+use crate::State;
+use crate::error::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::query_as;
+
+#[derive(Debug, Serialize)]
+pub struct Purchase {
+    pub id: i64,
+    pub product_id: i64,
+    pub buyer_id: i32,
+    pub price: i32,
+    pub payment_ref: Option<String>,
+    pub paid_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl State {
+    /// Records an unpaid purchase at the current price, buying twice returns the first purchase.
+    pub async fn purchase_product(&self, product_id: i64, buyer_id: i32) -> Result<Purchase> {
+        let store = query_as!(
+            Purchase,
+            "INSERT INTO purchase (product_id, buyer_id, price)
+            SELECT id, $2, COALESCE(price, 0) FROM Product WHERE id = $1
+            ON CONFLICT (product_id, buyer_id) DO UPDATE SET price = purchase.price
+            RETURNING id, product_id, buyer_id, price, payment_ref, paid_at, created_at",
+            product_id,
+            buyer_id,
+        )
+        .fetch_one(&self.pg)
+        .await?;
+        Ok(store)
+    }
+    /// Marks the purchase paid once the payment provider confirmed `payment_ref`.
+    pub async fn confirm_payment(&self, id: i64, payment_ref: &str) -> Result<Purchase> {
+        Ok(query_as!(
+            Purchase,
+            "UPDATE purchase SET payment_ref = $2, paid_at = now()
+            WHERE id = $1 AND paid_at IS NULL
+            RETURNING id, product_id, buyer_id, price, payment_ref, paid_at, created_at",
+            id,
+            payment_ref,
+        )
+        .fetch_one(&self.pg)
+        .await?)
+    }
+    pub async fn purchases_of(&self, buyer_id: i32) -> Result<Vec<Purchase>> {
+        Ok(query_as!(
+            Purchase,
+            "SELECT id, product_id, buyer_id, price, payment_ref, paid_at, created_at
+            FROM purchase
+            WHERE buyer_id = $1
+            ORDER BY created_at",
+            buyer_id,
+        )
+        .fetch_all(&self.pg)
+        .await?)
+    }
+}
@@ -0,0 +1,465 @@
+use crate::State;
+use crate::error::{Error, Result};
+use crate::products::artifact::{Artifact, artifact_platform, variant_name};
+use crate::products::format::{HEAD_BYTES, Inspection, Platform, inspect};
+use crate::storage::Blob;
+use axum::body::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, query_scalar};
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::info;
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+/// An upload sent in chunks, each one continues where `received` stopped.
+#[derive(Debug, Serialize)]
+pub struct Upload {
+    pub id: Uuid,
+    pub product_id: i64,
+    pub user_id: i32,
+    pub size: i64,
+    pub received: i64,
+    pub sha256: Option<String>,
+    /// Declared when the upload started, like the `platforms` of `upload_executable`.
+    pub platforms: Vec<String>,
+    pub variant: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+#[derive(Deserialize, Validate)]
+pub struct NewUpload {
+    #[validate(range(min = 1, message = "must be positive"))]
+    pub size: i64,
+    /// Checked once the last chunk arrived.
+    #[validate(custom(function = "sha256_hex"))]
+    pub sha256: Option<String>,
+    /// `linux-x86_64`, `windows`, ... the finished file has to be built for.
+    #[serde(default)]
+    pub platforms: Vec<String>,
+    #[validate(custom(function = "variant_name"))]
+    pub variant: Option<String>,
+}
+/// Where a resumable upload stands, `artifact` is set by the chunk that completes it.
+#[derive(Debug, Serialize)]
+pub struct UploadProgress {
+    pub upload: Upload,
+    pub artifact: Option<Artifact>,
+}
+
+fn sha256_hex(sha256: &str) -> std::result::Result<(), ValidationError> {
+    if sha256.len() == 64 && sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(());
+    }
+    Err(ValidationError::new("sha256").with_message("must be 64 hex digits".into()))
+}
+
+/// Writes `body` to `path` while hashing it and gives up past `max` bytes.
+pub async fn stage(body: impl Stream<Item = Result<Bytes>>, path: &Path, max: i64) -> Result<Blob> {
+    let staged = write_stream(body, path, max).await;
+    if staged.is_err() {
+        remove_staged(path).await;
+    }
+    staged
+}
+async fn write_stream(
+    body: impl Stream<Item = Result<Bytes>>,
+    path: &Path,
+    max: i64,
+) -> Result<Blob> {
+    let mut body = pin!(body);
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        size += i64::try_from(chunk.len())?;
+        if size > max {
+            return Err(Error::TooLarge(max));
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(Blob {
+        sha256: hex::encode(hasher.finalize()),
+        size,
+    })
+}
+async fn remove_staged(path: &Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await
+        && e.kind() != ErrorKind::NotFound
+    {
+        tracing::warn!("failed to remove staged upload {}: {e}", path.display());
+    }
+}
+/// Recognizes the staged file and checks it against the `declared` platforms.
+async fn inspect_staged(path: &Path, declared: &[Platform]) -> Result<Inspection> {
+    let mut head = Vec::new();
+    let file = tokio::fs::File::open(path).await?;
+    file.take(HEAD_BYTES as u64).read_to_end(&mut head).await?;
+    let inspection = inspect(&head).ok_or(Error::Datatype)?;
+    inspection.check(declared)?;
+    Ok(inspection)
+}
+
+impl State {
+    fn staging(&self, name: &str) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.config.storage.uploads)?;
+        Ok(self.config.storage.uploads.join(name))
+    }
+    /// Streams a whole build to the blob store and files it as an artifact of the product.
+    pub async fn upload_artifact(
+        &self,
+        product_id: i64,
+        body: impl Stream<Item = Result<Bytes>>,
+        platforms: &[Platform],
+        variant: Option<&str>,
+    ) -> Result<Artifact> {
+        let path = self.staging(&format!("{}.part", Uuid::new_v4()))?;
+        let blob = stage(body, &path, self.config.uploads.artifact_max_bytes).await?;
+        self.attach_staged(product_id, &path, blob, platforms, variant)
+            .await
+    }
+    async fn attach_staged(
+        &self,
+        product_id: i64,
+        path: &Path,
+        blob: Blob,
+        platforms: &[Platform],
+        variant: Option<&str>,
+    ) -> Result<Artifact> {
+        let checked = async {
+            let inspection = inspect_staged(path, platforms).await?;
+            let platform = artifact_platform(&inspection, platforms)?;
+            Ok::<_, Error>((inspection, platform))
+        };
+        let (inspection, platform) = match checked.await {
+            Ok(checked) => checked,
+            Err(e) => {
+                remove_staged(path).await;
+                return Err(e);
+            }
+        };
+        let blob = self.put_blob_file(path, blob, &inspection).await?;
+        self.add_artifact(product_id, platform, variant, &blob)
+            .await
+    }
+    pub async fn create_upload(
+        &self,
+        product_id: i64,
+        user_id: i32,
+        new: NewUpload,
+    ) -> Result<Upload> {
+        let max = self.config.uploads.artifact_max_bytes;
+        if new.size > max {
+            return Err(Error::TooLarge(max));
+        }
+        let platforms: Vec<Platform> = new
+            .platforms
+            .iter()
+            .map(|platform| platform.parse())
+            .collect::<Result<_>>()?;
+        Ok(query_as!(
+            Upload,
+            "INSERT INTO upload (id, product_id, user_id, size, sha256, platforms, variant, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, product_id, user_id, size, received, sha256, platforms, variant, created_at, expires_at",
+            Uuid::new_v4(),
+            product_id,
+            user_id,
+            new.size,
+            new.sha256.map(|sha256| sha256.to_lowercase()),
+            &platforms.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            new.variant,
+            Utc::now() + Duration::hours(self.config.uploads.resumable_ttl_hours),
+        )
+        .fetch_one(&self.pg)
+        .await?)
+    }
+    /// An unexpired upload `user_id` started.
+    pub async fn get_upload(&self, product_id: i64, id: Uuid, user_id: i32) -> Result<Upload> {
+        Ok(query_as!(
+            Upload,
+            "SELECT id, product_id, user_id, size, received, sha256, platforms, variant, created_at, expires_at
+            FROM upload
+            WHERE id = $1 AND product_id = $2 AND user_id = $3 AND expires_at > now()",
+            id,
+            product_id,
+            user_id,
+        )
+        .fetch_one(&self.pg)
+        .await?)
+    }
+    /// Appends the chunk starting at `offset`, the last one completes the upload.
+    pub async fn append_upload(
+        &self,
+        upload: Upload,
+        offset: i64,
+        body: impl Stream<Item = Result<Bytes>>,
+    ) -> Result<UploadProgress> {
+        if offset != upload.received {
+            return Err(Error::UploadOffset(upload.received));
+        }
+        let max = (upload.size - upload.received).min(self.config.uploads.chunk_max_bytes);
+        // every request gets its own part, racing chunks for one offset never share a file
+        let part = self.staging(&format!("{}.{}.part", upload.id, Uuid::new_v4()))?;
+        let chunk = stage(body, &part, max).await?;
+        let appended = self.append_part(&upload, &part, chunk.size).await;
+        remove_staged(&part).await;
+        let upload = appended?;
+        if upload.received < upload.size {
+            return Ok(UploadProgress {
+                upload,
+                artifact: None,
+            });
+        }
+        let artifact = self.finish_upload(&upload).await?;
+        Ok(UploadProgress {
+            upload,
+            artifact: Some(artifact),
+        })
+    }
+    async fn append_part(&self, upload: &Upload, part: &Path, size: i64) -> Result<Upload> {
+        let mut tx = self.pg.begin().await?;
+        // a concurrent chunk for the same offset waits for this one, then matches nothing
+        let Some(appended) = query_as!(
+            Upload,
+            "UPDATE upload SET received = received + $3
+            WHERE id = $1 AND received = $2
+            RETURNING id, product_id, user_id, size, received, sha256, platforms, variant, created_at, expires_at",
+            upload.id,
+            upload.received,
+            size,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            let received = query_scalar!("SELECT received FROM upload WHERE id = $1", upload.id)
+                .fetch_one(&self.pg)
+                .await?;
+            return Err(Error::UploadOffset(received));
+        };
+        let mut staged = tokio::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.staging(&upload.id.to_string())?)
+            .await?;
+        // drops whatever a failed chunk left behind
+        staged.set_len(upload.received.try_into()?).await?;
+        staged.seek(SeekFrom::End(0)).await?;
+        tokio::io::copy(&mut tokio::fs::File::open(part).await?, &mut staged).await?;
+        staged.flush().await?;
+        tx.commit().await?;
+        Ok(appended)
+    }
+    async fn finish_upload(&self, upload: &Upload) -> Result<Artifact> {
+        let path = self.staging(&upload.id.to_string())?;
+        let blob = Blob::of_file(&path).await?;
+        query!("DELETE FROM upload WHERE id = $1", upload.id)
+            .execute(&self.pg)
+            .await?;
+        if upload
+            .sha256
+            .as_ref()
+            .is_some_and(|sha256| *sha256 != blob.sha256)
+        {
+            remove_staged(&path).await;
+            return Err(Error::ChecksumMismatch);
+        }
+        let platforms: Vec<Platform> = upload
+            .platforms
+            .iter()
+            .map(|platform| platform.parse())
+            .collect::<Result<_>>()?;
+        self.attach_staged(
+            upload.product_id,
+            &path,
+            blob,
+            &platforms,
+            upload.variant.as_deref(),
+        )
+        .await
+    }
+    pub async fn cancel_upload(&self, upload: &Upload) -> Result<()> {
+        query!("DELETE FROM upload WHERE id = $1", upload.id)
+            .execute(&self.pg)
+            .await?;
+        remove_staged(&self.staging(&upload.id.to_string())?).await;
+        Ok(())
+    }
+    /// Forgets expired uploads and removes staged files nobody touched for as long.
+    pub async fn purge_expired_uploads(&self) -> Result<usize> {
+        let expired = query_scalar!("DELETE FROM upload WHERE expires_at < now() RETURNING id")
+            .fetch_all(&self.pg)
+            .await?;
+        let ttl = std::time::Duration::from_secs(
+            u64::try_from(self.config.uploads.resumable_ttl_hours)? * 3600,
+        );
+        let mut files = tokio::fs::read_dir(self.staging("")?).await?;
+        while let Some(file) = files.next_entry().await? {
+            let modified = file.metadata().await?.modified()?;
+            if modified.elapsed().unwrap_or_default() > ttl {
+                remove_staged(&file.path()).await;
+            }
+        }
+        if !expired.is_empty() {
+            info!("{} expired uploads purged", expired.len());
+        }
+        Ok(expired.len())
+    }
+}
+
+#[cfg(test)]
+fn chunks(parts: &[&[u8]]) -> impl Stream<Item = Result<Bytes>> + use<> {
+    let parts: Vec<_> = parts
+        .iter()
+        .map(|part| Ok(Bytes::copy_from_slice(part)))
+        .collect();
+    futures_util::stream::iter(parts)
+}
+#[cfg(test)]
+async fn upload_product(
+    state: &State,
+    owner: &str,
+) -> (crate::user::model::User, crate::products::model::Product) {
+    use crate::products::model::NewProduct;
+    use crate::user::model::NewUser;
+    let owner = state
+        .create_user(axum::Json(NewUser::test(owner)))
+        .await
+        .unwrap();
+    let product = state
+        .new_product(axum::Json(NewProduct {
+            name: "chunked".to_string(),
+            description: "arrives in pieces".to_string(),
+            price: 0,
+            owner_id: owner.id,
+            executable: None,
+        }))
+        .await
+        .unwrap();
+    (owner, product)
+}
+
+#[tokio::test]
+async fn upload_t() {
+    use crate::products::format::{parse_platforms, test_executable};
+    let state = crate::test::state().await;
+    let (owner, product) = upload_product(&state, "uploader").await;
+
+    let exe = test_executable(b" streamed");
+    let windows = parse_platforms("windows-x86_64").unwrap();
+    let streamed = state
+        .upload_artifact(product.id, chunks(&[&exe[..2], &exe[2..]]), &windows, None)
+        .await
+        .unwrap();
+    assert_eq!(streamed.sha256, Blob::of(&exe).unwrap().sha256);
+    assert_eq!(streamed.platform().unwrap(), windows[0]);
+
+    let content = test_executable(b" resumed upload");
+    let new = NewUpload {
+        size: content.len().try_into().unwrap(),
+        sha256: Some(Blob::of(&content).unwrap().sha256.to_uppercase()),
+        platforms: vec!["Windows-AMD64".to_string()],
+        variant: Some("portable".to_string()),
+    };
+    let upload = state
+        .create_upload(product.id, owner.id, new)
+        .await
+        .unwrap();
+    assert_eq!(upload.platforms, ["windows-x86_64"]);
+    let progress = state
+        .append_upload(upload, 0, chunks(&[&content[..10]]))
+        .await
+        .unwrap();
+    assert_eq!(
+        (progress.upload.received, progress.artifact.is_none()),
+        (10, true)
+    );
+    let id = progress.upload.id;
+    let upload = state.get_upload(product.id, id, owner.id).await.unwrap();
+    assert!(matches!(
+        state
+            .append_upload(upload, 0, chunks(&[&content[..10]]))
+            .await,
+        Err(Error::UploadOffset(10))
+    ));
+    let upload = state.get_upload(product.id, id, owner.id).await.unwrap();
+    let progress = state
+        .append_upload(upload, 10, chunks(&[&content[10..]]))
+        .await
+        .unwrap();
+    let resumed = progress.artifact.unwrap();
+    assert_eq!(resumed.sha256, Blob::of(&content).unwrap().sha256);
+    assert_eq!(
+        (resumed.size, resumed.variant.as_deref()),
+        (105, Some("portable"))
+    );
+    assert_eq!(state.artifacts_of(product.id).await.unwrap().len(), 2);
+    assert!(state.get_upload(product.id, id, owner.id).await.is_err());
+
+    state.delete_product(product.id).await.unwrap();
+    state.delete_user(owner.username).await.unwrap();
+}
+
+#[tokio::test]
+async fn upload_rejected_t() {
+    use crate::products::format::{parse_platforms, test_executable};
+    let state = crate::test::state().await;
+    let (owner, product) = upload_product(&state, "rejected").await;
+
+    assert!(matches!(
+        state
+            .upload_artifact(product.id, chunks(&[b"MZ, but a DOS stub"]), &[], None)
+            .await,
+        Err(Error::Datatype)
+    ));
+    let linux = parse_platforms("linux").unwrap();
+    assert!(matches!(
+        state
+            .upload_artifact(product.id, chunks(&[&test_executable(b"")]), &linux, None)
+            .await,
+        Err(Error::PlatformMismatch { .. })
+    ));
+    let path = state.staging("limit").unwrap();
+    assert!(matches!(
+        stage(chunks(&[b"MZ", b"1234"]), &path, 4).await,
+        Err(Error::TooLarge(4))
+    ));
+    assert!(!path.exists());
+
+    let new = NewUpload {
+        size: 4,
+        sha256: None,
+        platforms: vec!["beos".to_string()],
+        variant: None,
+    };
+    assert!(matches!(
+        state.create_upload(product.id, owner.id, new).await,
+        Err(Error::UnknownPlatform(_))
+    ));
+    let new = NewUpload {
+        size: 4,
+        sha256: Some(Blob::of(b"else").unwrap().sha256),
+        platforms: Vec::new(),
+        variant: None,
+    };
+    let upload = state
+        .create_upload(product.id, owner.id, new)
+        .await
+        .unwrap();
+    assert!(matches!(
+        state.append_upload(upload, 0, chunks(&[b"MZ!!"])).await,
+        Err(Error::ChecksumMismatch)
+    ));
+
+    state.delete_product(product.id).await.unwrap();
+    state.delete_user(owner.username).await.unwrap();
+}
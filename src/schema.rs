@@ -0,0 +1,38 @@
+use crate::error::Result;
+use sqlx::PgPool;
+use sqlx::migrate::Migrator;
+
+/// The files of `migrations/`, built into the binary.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Applies the pending migrations.
+pub async fn migrate(pg: &PgPool) -> Result<()> {
+    MIGRATOR.run(pg).await?;
+    Ok(())
+}
+/// Versions of the embedded migrations the database hasn't run yet.
+pub async fn pending_migrations(pg: &PgPool) -> Result<Vec<i64>> {
+    let applied: Vec<i64> =
+        match sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(pg)
+            .await
+        {
+            Ok(applied) => applied,
+            // a database that never ran a migration has no bookkeeping table either
+            Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("42P01") => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+    Ok(MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .filter(|version| !applied.contains(version))
+        .collect())
+}
+
+#[tokio::test]
+async fn schema_t() {
+    let state = crate::test::state().await;
+    migrate(&state.pg).await.unwrap();
+    assert!(pending_migrations(&state.pg).await.unwrap().is_empty());
+    assert!(MIGRATOR.iter().count() > 0);
+}
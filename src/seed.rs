@@ -0,0 +1,70 @@
+use crate::State;
+use crate::error::Result;
+use crate::products::model::NewProduct;
+use crate::user::model::{NewUser, UserRole};
+use axum::Json;
+use sqlx::query;
+use tracing::info;
+
+/// Password of the demo accounts, printed by the admin CLI.
+pub const DEMO_PASSWORD: &str = "demo1234";
+
+impl State {
+    /// A verified seller with a few products and a verified buyer who bought one.
+    /// Returns false when the demo accounts already exist.
+    pub async fn seed_demo(&self) -> Result<bool> {
+        if self
+            .find_user_by_email("seller@demo.devmarket".to_string())
+            .await?
+            .is_some()
+        {
+            return Ok(false);
+        }
+        let seller = self
+            .create_user(Json(NewUser::new(
+                "seller@demo.devmarket".to_string(),
+                "demoseller".to_string(),
+                DEMO_PASSWORD.to_string(),
+                UserRole::Seller,
+            )?))
+            .await?;
+        let buyer = self
+            .create_user(Json(NewUser::new(
+                "buyer@demo.devmarket".to_string(),
+                "demobuyer".to_string(),
+                DEMO_PASSWORD.to_string(),
+                UserRole::Buyer,
+            )?))
+            .await?;
+        query!(
+            r#"UPDATE "User" SET email_verified_at = now() WHERE id = ANY($1)"#,
+            &[seller.id, buyer.id],
+        )
+        .execute(&self.pg)
+        .await?;
+        let products = [
+            ("hexview", "A fast hex viewer for large files", 0),
+            ("tinyhttp", "Single binary static file server", 5),
+            ("logsieve", "Filters and colors structured logs", 12),
+        ];
+        let mut first = None;
+        for (name, description, price) in products {
+            let product = self
+                .new_product(Json(NewProduct {
+                    name: name.to_string(),
+                    description: description.to_string(),
+                    price,
+                    owner_id: seller.id,
+                    executable: None,
+                }))
+                .await?;
+            first.get_or_insert(product.id);
+        }
+        if let Some(product) = first {
+            let purchase = self.purchase_product(product, buyer.id).await?;
+            self.confirm_payment(purchase.id, "demo").await?;
+        }
+        info!("demo data seeded");
+        Ok(true)
+    }
+}
@@ -0,0 +1,118 @@
+use crate::error::{Error, Result};
+use crate::storage::{BlobStore, BlobStream};
+use futures_util::StreamExt;
+use std::io::{ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+/// Blobs as files under `dir`, sharded by the first two characters of the key.
+pub struct LocalStore {
+    dir: PathBuf,
+}
+impl LocalStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(dir.join("tmp"))?;
+        Ok(Self { dir })
+    }
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        if key.len() < 3 || !key.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::NotFound);
+        }
+        Ok(self.dir.join(&key[..2]).join(key))
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for LocalStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(shard) = path.parent() {
+            tokio::fs::create_dir_all(shard).await?;
+        }
+        // readers never see half a file
+        let tmp = self.dir.join("tmp").join(uuid::Uuid::new_v4().to_string());
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        let target = self.path(key)?;
+        if let Some(shard) = target.parent() {
+            tokio::fs::create_dir_all(shard).await?;
+        }
+        if tokio::fs::rename(path, &target).await.is_err() {
+            // not on the same filesystem
+            let tmp = self.dir.join("tmp").join(uuid::Uuid::new_v4().to_string());
+            tokio::fs::copy(path, &tmp).await?;
+            tokio::fs::rename(&tmp, &target).await?;
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::NotFound),
+            read => Ok(read?),
+        }
+    }
+    async fn read(&self, key: &str, range: Option<Range<u64>>) -> Result<BlobStream> {
+        let mut file = match tokio::fs::File::open(self.path(key)?).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+            opened => opened?,
+        };
+        let length = match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                range.end - range.start
+            }
+            None => u64::MAX,
+        };
+        Ok(ReaderStream::new(file.take(length))
+            .map(|chunk| Ok(chunk?))
+            .boxed())
+    }
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(tokio::fs::try_exists(self.path(key)?).await?)
+    }
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            removed => Ok(removed?),
+        }
+    }
+}
+
+#[tokio::test]
+async fn local_t() {
+    let dir = std::env::temp_dir().join(format!("devmarket-blobs-{}", uuid::Uuid::new_v4()));
+    let store = LocalStore::new(&dir).unwrap();
+    let blob = crate::storage::Blob::of(b"MZ local").unwrap();
+    assert!(!store.exists(&blob.sha256).await.unwrap());
+    store.put(&blob.sha256, b"MZ local".to_vec()).await.unwrap();
+    store.put(&blob.sha256, b"MZ local".to_vec()).await.unwrap();
+    assert!(store.exists(&blob.sha256).await.unwrap());
+    assert_eq!(store.get(&blob.sha256).await.unwrap(), b"MZ local");
+    let mut part = store.read(&blob.sha256, Some(3..6)).await.unwrap();
+    assert_eq!(part.next().await.unwrap().unwrap(), &b"loc"[..]);
+    assert!(dir.join(&blob.sha256[..2]).join(&blob.sha256).is_file());
+    assert!(store.get("../../etc/passwd").await.is_err());
+
+    let staged = dir.join("staged");
+    std::fs::write(&staged, b"MZ file").unwrap();
+    let file = crate::storage::Blob::of_file(&staged).await.unwrap();
+    assert_eq!(file, crate::storage::Blob::of(b"MZ file").unwrap());
+    store.put_file(&file.sha256, &staged).await.unwrap();
+    assert!(!staged.exists());
+    assert_eq!(store.get(&file.sha256).await.unwrap(), b"MZ file");
+
+    store.delete(&blob.sha256).await.unwrap();
+    store.delete(&blob.sha256).await.unwrap();
+    assert!(matches!(
+        store.get(&blob.sha256).await,
+        Err(Error::NotFound)
+    ));
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,264 @@
+use crate::State;
+use crate::config::Config;
+use crate::error::Result;
+use crate::products::format::{Inspection, inspect};
+use axum::body::Bytes;
+use chrono::{Duration, Utc};
+use futures_util::stream::BoxStream;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, Transaction, query, query_scalar};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tracing::info;
+
+pub mod local;
+pub mod s3;
+
+pub type BlobStream = BoxStream<'static, Result<Bytes>>;
+
+/// Unreferenced blobs younger than this are kept, an upload may be about to use them.
+const GRACE_MINUTES: i64 = 60;
+
+/// Where uploaded files go. Keys are the hex SHA-256 of the content, so putting the
+/// same key twice stores the same bytes.
+#[async_trait::async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+    /// Moves the file at `path` into the store without reading it into memory.
+    async fn put_file(&self, key: &str, path: &Path) -> Result<()>;
+    async fn get(&self, key: &str) -> Result<Vec<u8>>;
+    /// Streams `range` of the blob, all of it when `None`.
+    async fn read(&self, key: &str, range: Option<Range<u64>>) -> Result<BlobStream>;
+    async fn exists(&self, key: &str) -> Result<bool>;
+    /// Deleting a missing blob isn't an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// The S3 store when `[s3]` is configured, the local directory otherwise.
+pub fn open(config: &Config) -> Result<Arc<dyn BlobStore>> {
+    Ok(match &config.s3 {
+        Some(s3) => Arc::new(s3::S3Store::new(s3.clone())),
+        None => Arc::new(local::LocalStore::new(&config.storage.blobs)?),
+    })
+}
+
+/// A stored file as products refer to it.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Blob {
+    pub sha256: String,
+    pub size: i64,
+}
+impl Blob {
+    pub fn of(data: &[u8]) -> Result<Self> {
+        Ok(Self {
+            sha256: hex::encode(Sha256::digest(data)),
+            size: data.len().try_into()?,
+        })
+    }
+    pub async fn of_file(path: &Path) -> Result<Self> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read;
+        }
+        Ok(Self {
+            sha256: hex::encode(hasher.finalize()),
+            size: size.try_into()?,
+        })
+    }
+}
+
+/// Serializes the check-put-record of an upload with the purge of the same blob, so the
+/// purge can't delete a file an upload just decided to reuse.
+async fn lock_blob(pg: &PgPool, sha256: &str) -> Result<Transaction<'static, Postgres>> {
+    let mut tx = pg.begin().await?;
+    query!(
+        r#"SELECT 1 AS "locked!" FROM pg_advisory_xact_lock(hashtext($1))"#,
+        sha256
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    Ok(tx)
+}
+
+async fn record_blob(
+    tx: &mut Transaction<'static, Postgres>,
+    blob: &Blob,
+    inspection: &Inspection,
+) -> Result<()> {
+    // a fresh created_at keeps the purge away from it until the product row exists
+    query!(
+        "INSERT INTO blob (sha256, size, inspection) VALUES ($1, $2, $3)
+        ON CONFLICT (sha256) DO UPDATE SET created_at = now(), inspection = $3",
+        blob.sha256,
+        blob.size,
+        serde_json::to_value(inspection)?,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+impl State {
+    /// Stores `data` once, however many products upload it.
+    pub async fn put_blob(&self, data: Vec<u8>, inspection: &Inspection) -> Result<Blob> {
+        let blob = Blob::of(&data)?;
+        let mut tx = lock_blob(&self.pg, &blob.sha256).await?;
+        if !self.blobs.exists(&blob.sha256).await? {
+            self.blobs.put(&blob.sha256, data).await?;
+        }
+        record_blob(&mut tx, &blob, inspection).await?;
+        tx.commit().await?;
+        Ok(blob)
+    }
+    /// Like `put_blob` for content already on disk, `blob` describes `path`, which is gone after.
+    pub async fn put_blob_file(
+        &self,
+        path: &Path,
+        blob: Blob,
+        inspection: &Inspection,
+    ) -> Result<Blob> {
+        let mut tx = lock_blob(&self.pg, &blob.sha256).await?;
+        if self.blobs.exists(&blob.sha256).await? {
+            tokio::fs::remove_file(path).await?;
+        } else {
+            self.blobs.put_file(&blob.sha256, path).await?;
+        }
+        record_blob(&mut tx, &blob, inspection).await?;
+        tx.commit().await?;
+        Ok(blob)
+    }
+    pub async fn get_blob(&self, sha256: &str) -> Result<Vec<u8>> {
+        self.blobs.get(sha256).await
+    }
+    /// What the blob was found to be, `None` for blobs stored before inspection existed.
+    pub async fn blob_inspection(&self, sha256: &str) -> Result<Option<Inspection>> {
+        let inspection = query_scalar!("SELECT inspection FROM blob WHERE sha256 = $1", sha256)
+            .fetch_optional(&self.pg)
+            .await?
+            .flatten();
+        Ok(inspection.map(serde_json::from_value).transpose()?)
+    }
+    /// Deletes the blobs no product or artifact refers to anymore.
+    pub async fn purge_unused_blobs(&self) -> Result<usize> {
+        let candidates = query_scalar!(
+            "SELECT sha256 FROM blob
+            WHERE created_at < $1
+            AND NOT EXISTS (SELECT 1 FROM Product WHERE executable_sha256 = blob.sha256)
+            AND NOT EXISTS (SELECT 1 FROM artifact WHERE artifact.sha256 = blob.sha256)",
+            Utc::now() - Duration::minutes(GRACE_MINUTES),
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        let mut purged = 0;
+        for sha256 in candidates {
+            // an upload may have picked it up since, check again under the lock
+            let mut tx = lock_blob(&self.pg, &sha256).await?;
+            let unused = query!(
+                "DELETE FROM blob
+                WHERE sha256 = $1 AND created_at < $2
+                AND NOT EXISTS (SELECT 1 FROM Product WHERE executable_sha256 = blob.sha256)
+                AND NOT EXISTS (SELECT 1 FROM artifact WHERE artifact.sha256 = blob.sha256)",
+                sha256,
+                Utc::now() - Duration::minutes(GRACE_MINUTES),
+            )
+            .execute(&mut *tx)
+            .await?;
+            if unused.rows_affected() > 0 {
+                self.blobs.delete(&sha256).await?;
+                purged += 1;
+            }
+            tx.commit().await?;
+        }
+        if purged > 0 {
+            info!("{purged} unused blobs purged");
+        }
+        Ok(purged)
+    }
+    /// Copies the executables the blob store migration left in the database to the store.
+    pub async fn move_legacy_blobs(&self) -> Result<usize> {
+        let mut moved = 0;
+        while let Some(legacy) = query!("SELECT sha256, data FROM legacy_blob LIMIT 1")
+            .fetch_optional(&self.pg)
+            .await?
+        {
+            // whatever legacy executables turn out to be, they were accepted back then
+            if let Some(inspection) = inspect(&legacy.data) {
+                query!(
+                    "UPDATE blob SET inspection = $2 WHERE sha256 = $1",
+                    legacy.sha256,
+                    serde_json::to_value(inspection)?,
+                )
+                .execute(&self.pg)
+                .await?;
+            }
+            self.blobs.put(&legacy.sha256, legacy.data).await?;
+            query!("DELETE FROM legacy_blob WHERE sha256 = $1", legacy.sha256)
+                .execute(&self.pg)
+                .await?;
+            moved += 1;
+        }
+        if moved > 0 {
+            info!("{moved} executables moved from the database to the blob store");
+        }
+        Ok(moved)
+    }
+}
+
+#[tokio::test]
+async fn storage_t() {
+    use crate::products::model::NewProduct;
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let owner = state
+        .create_user(axum::Json(NewUser::test("blobs")))
+        .await
+        .unwrap();
+    let content = crate::products::format::test_executable(uuid::Uuid::new_v4().as_bytes());
+    let product = |name: &str| NewProduct {
+        name: name.to_string(),
+        description: "same binary".to_string(),
+        price: 1,
+        owner_id: owner.id,
+        executable: Some(content.clone()),
+    };
+    let first = state
+        .new_product(axum::Json(product("first")))
+        .await
+        .unwrap();
+    let second = state
+        .new_product(axum::Json(product("second")))
+        .await
+        .unwrap();
+    let sha256 = first.executable_sha256.clone().unwrap();
+    assert_eq!(second.executable_sha256.as_ref(), Some(&sha256));
+    assert_eq!(first.executable_size, Some(106));
+    assert_eq!(state.get_blob(&sha256).await.unwrap(), content);
+    let inspection = state.blob_inspection(&sha256).await.unwrap().unwrap();
+    assert_eq!(inspection.platforms[0].to_string(), "windows-x86_64");
+
+    state.delete_product(first.id).await.unwrap();
+    state.delete_product(second.id).await.unwrap();
+    // pretend the grace period is over
+    query!(
+        "UPDATE blob SET created_at = now() - interval '2 hours' WHERE sha256 = $1",
+        sha256
+    )
+    .execute(&state.pg)
+    .await
+    .unwrap();
+    assert!(state.purge_unused_blobs().await.unwrap() >= 1);
+    assert!(!state.blobs.exists(&sha256).await.unwrap());
+
+    state.delete_user(owner.username).await.unwrap();
+}
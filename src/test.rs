@@ -1 +1,9 @@
+use crate::State;
+use crate::config::{Cli, Config};
 
+pub async fn state() -> State {
+    dotenvy::dotenv().ok();
+    let config = Config::from_sources(&Cli::default(), std::env::vars()).unwrap();
+    let pool = crate::connect(&config).await.unwrap();
+    State::new(config, pool).await.unwrap()
+}
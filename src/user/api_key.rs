@@ -0,0 +1,227 @@
+use crate::State;
+use crate::error::{Error, Result};
+use crate::user::Clains;
+use crate::user::refresh::{hash_token, random_token};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use tracing::warn;
+use uuid::Uuid;
+use validator::Validate;
+
+/// What an API key may do, interactive logins are not restricted by scopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "products:read")]
+    ProductsRead,
+    #[serde(rename = "products:write")]
+    ProductsWrite,
+}
+impl Scope {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::ProductsRead => "products:read",
+            Self::ProductsWrite => "products:write",
+        }
+    }
+    fn parse(scope: &str) -> Option<Self> {
+        [Self::ProductsRead, Self::ProductsWrite]
+            .into_iter()
+            .find(|known| known.as_str() == scope)
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct NewApiKey {
+    #[validate(length(min = 1, max = 64, message = "must be 1 to 64 characters"))]
+    pub name: String,
+    #[validate(length(min = 1, message = "must name at least one scope"))]
+    pub scopes: Vec<Scope>,
+    #[validate(range(min = 1, max = 365, message = "must be 1 to 365 days"))]
+    pub expires_in_days: Option<i64>,
+}
+#[derive(Debug, Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    /// The only time the full key is visible.
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
+
+impl State {
+    pub async fn create_api_key(&self, user_id: i32, data: NewApiKey) -> Result<CreatedApiKey> {
+        let prefix = random_token()[..12].to_string();
+        let key = format!("dm_{prefix}_{}", random_token());
+        let scopes: Vec<String> = data
+            .scopes
+            .iter()
+            .map(|scope| scope.as_str().to_string())
+            .collect();
+        let api_key = query_as!(
+            ApiKey,
+            "INSERT INTO api_key (user_id, name, prefix, key_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, name, prefix, scopes, created_at, expires_at, last_used_at, revoked_at",
+            user_id,
+            data.name,
+            prefix,
+            hash_token(&key),
+            &scopes,
+            data.expires_in_days
+                .map(|days| Utc::now() + Duration::days(days)),
+        )
+        .fetch_one(&self.pg)
+        .await?;
+        Ok(CreatedApiKey { key, api_key })
+    }
+    pub async fn api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>> {
+        Ok(query_as!(
+            ApiKey,
+            "SELECT id, name, prefix, scopes, created_at, expires_at, last_used_at, revoked_at
+            FROM api_key WHERE user_id = $1
+            ORDER BY created_at DESC",
+            user_id,
+        )
+        .fetch_all(&self.pg)
+        .await?)
+    }
+    pub async fn revoke_api_key(&self, user_id: i32, id: i64) -> Result<ApiKey> {
+        Ok(query_as!(
+            ApiKey,
+            "UPDATE api_key SET revoked_at = COALESCE(revoked_at, now())
+            WHERE id = $1 AND user_id = $2
+            RETURNING id, name, prefix, scopes, created_at, expires_at, last_used_at, revoked_at",
+            id,
+            user_id,
+        )
+        .fetch_one(&self.pg)
+        .await?)
+    }
+    /// Resolves `dm_<prefix>_<secret>` to claims limited to the scopes of the key.
+    pub async fn authenticate_api_key(&self, key: &str) -> Result<Clains> {
+        let prefix = key
+            .strip_prefix("dm_")
+            .and_then(|rest| rest.split_once('_'))
+            .ok_or(Error::InvalidToken)?
+            .0;
+        let stored = query!(
+            r#"SELECT api_key.id, api_key.key_hash, api_key.scopes, api_key.expires_at,
+                "User".username, "User".role AS "role: crate::user::model::Role"
+            FROM api_key JOIN "User" ON "User".id = api_key.user_id
+            WHERE api_key.prefix = $1 AND api_key.revoked_at IS NULL AND "User".deleted_at IS NULL
+            AND (api_key.expires_at IS NULL OR api_key.expires_at > now())"#,
+            prefix,
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        .ok_or(Error::InvalidToken)?;
+        if stored.key_hash != hash_token(key) {
+            return Err(Error::InvalidToken);
+        }
+
+        let pg = self.pg.clone();
+        tokio::spawn(async move {
+            let touched = query!(
+                "UPDATE api_key SET last_used_at = now()
+                WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < now() - interval '1 minute')",
+                stored.id,
+            )
+            .execute(&pg)
+            .await;
+            if let Err(e) = touched {
+                warn!("failed to record api key use: {e}");
+            }
+        });
+
+        let now = Utc::now();
+        let exp = stored.expires_at.unwrap_or(now + Duration::minutes(1));
+        Ok(Clains {
+            username: stored.username,
+            role: stored.role,
+            exp: usize::try_from(exp.timestamp())?,
+            iat: usize::try_from(now.timestamp())?,
+            iat_ms: now.timestamp_millis(),
+            jti: Uuid::new_v4(),
+            mfa_pending: false,
+            sid: None,
+            act: None,
+            scopes: Some(
+                stored
+                    .scopes
+                    .iter()
+                    .filter_map(|scope| Scope::parse(scope))
+                    .collect(),
+            ),
+        })
+    }
+}
+
+#[tokio::test]
+async fn api_key_t() {
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let user = state
+        .create_user(axum::Json(NewUser::test("apikey")))
+        .await
+        .unwrap();
+
+    let created = state
+        .create_api_key(
+            user.id,
+            NewApiKey {
+                name: "ci".to_string(),
+                scopes: vec![Scope::ProductsWrite],
+                expires_in_days: None,
+            },
+        )
+        .await
+        .unwrap();
+    let claims = state.authenticate_api_key(&created.key).await.unwrap();
+    assert_eq!(claims.username, user.username);
+    assert!(claims.require_scope(Scope::ProductsWrite).is_ok());
+    assert!(claims.require_scope(Scope::ProductsRead).is_err());
+
+    let forged = format!("dm_{}_{}", created.api_key.prefix, "0".repeat(64));
+    assert!(state.authenticate_api_key(&forged).await.is_err());
+
+    state
+        .revoke_api_key(user.id, created.api_key.id)
+        .await
+        .unwrap();
+    assert!(state.authenticate_api_key(&created.key).await.is_err());
+
+    // signing the user out everywhere takes their keys along
+    let reader = NewApiKey {
+        name: "reader".to_string(),
+        scopes: vec![Scope::ProductsRead],
+        expires_in_days: None,
+    };
+    assert!(reader.validate().is_ok());
+    let unbounded = NewApiKey {
+        name: String::new(),
+        scopes: Vec::new(),
+        expires_in_days: Some(i64::MAX),
+    };
+    let errors = unbounded.validate().unwrap_err();
+    assert_eq!(errors.field_errors().len(), 3);
+    let created = state.create_api_key(user.id, reader).await.unwrap();
+    assert!(state.authenticate_api_key(&created.key).await.is_ok());
+    state
+        .revoke_user_tokens(user.username.clone())
+        .await
+        .unwrap();
+    assert!(state.authenticate_api_key(&created.key).await.is_err());
+
+    state.delete_user(user.username).await.unwrap();
+}
@@ -0,0 +1,212 @@
+use crate::State;
+use crate::audit::Audit;
+use crate::error::{Error, Result};
+use crate::user::model::{Role, User};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use sqlx::{query, query_as};
+use tracing::info;
+
+/// How long a deleted account can still be restored before it is purged.
+pub const RESTORE_DAYS: i64 = 30;
+
+/// What happens to the products of a seller whose account is deleted.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProductPolicy {
+    /// Hide them from the catalogue, buyers keep access. Restoring the account lists them again.
+    #[default]
+    Unlist,
+    /// Hand them over to another active seller.
+    TransferTo(String),
+}
+
+impl State {
+    /// Soft-deletes the account, applies `policy` to its products and signs it out everywhere.
+    pub async fn deactivate_user(&self, username: String, policy: &ProductPolicy) -> Result<User> {
+        let mut tx = self.pg.begin().await?;
+        let user = query_as::<_, User>(
+            r#"SELECT * FROM "User" WHERE username = $1 AND deleted_at IS NULL FOR UPDATE"#,
+        )
+        .bind(&username)
+        .fetch_one(&mut *tx)
+        .await?;
+        match policy {
+            ProductPolicy::Unlist => {
+                query!(
+                    "UPDATE Product SET unlisted_at = now() WHERE owner_id = $1 AND unlisted_at IS NULL",
+                    user.id,
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            ProductPolicy::TransferTo(seller) => {
+                let heir = query_as::<_, User>(
+                    r#"SELECT * FROM "User" WHERE username = $1 AND deleted_at IS NULL"#,
+                )
+                .bind(seller)
+                .fetch_optional(&mut *tx)
+                .await?
+                .filter(|heir| heir.role == Role::Seller && heir.id != user.id)
+                .ok_or(Error::InvalidTransfer)?;
+                query!(
+                    "UPDATE Product SET owner_id = $2 WHERE owner_id = $1",
+                    user.id,
+                    heir.id,
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        // now() is fixed for the transaction, so unlisted_at matches deleted_at exactly
+        let user = query_as::<_, User>(
+            r#"
+            UPDATE "User" SET deleted_at = now()
+            WHERE id = $1
+            RETURNING id, email, username, password, role, email_verified_at, deleted_at, erased_at
+            "#,
+        )
+        .bind(user.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.revoke_user_tokens(username).await?;
+        Ok(user)
+    }
+    /// A deleted account that is still inside the restore window.
+    pub async fn deleted_user(&self, username: String) -> Result<Option<User>> {
+        Ok(query_as::<_, User>(
+            r#"SELECT * FROM "User" WHERE username = $1 AND deleted_at > $2 AND erased_at IS NULL"#,
+        )
+        .bind(username)
+        .bind(Utc::now() - Duration::days(RESTORE_DAYS))
+        .fetch_optional(&self.pg)
+        .await?)
+    }
+    /// Reactivates the account and lists again the products its deletion unlisted.
+    pub async fn restore_user(&self, username: String) -> Result<User> {
+        let deleted = self
+            .deleted_user(username)
+            .await?
+            .ok_or(Error::InvalidUser)?;
+        let mut tx = self.pg.begin().await?;
+        query!(
+            "UPDATE Product SET unlisted_at = NULL WHERE owner_id = $1 AND unlisted_at = $2",
+            deleted.id,
+            deleted.deleted_at,
+        )
+        .execute(&mut *tx)
+        .await?;
+        let user = query_as::<_, User>(
+            r#"
+            UPDATE "User" SET deleted_at = NULL
+            WHERE id = $1
+            RETURNING id, email, username, password, role, email_verified_at, deleted_at, erased_at
+            "#,
+        )
+        .bind(deleted.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+    /// Removes the accounts whose restore window is over, their products lose their owner.
+    pub async fn purge_deleted_users(&self) -> Result<usize> {
+        let purged = query_as::<_, User>(
+            r#"
+            DELETE FROM "User" WHERE deleted_at < $1 AND erased_at IS NULL
+            RETURNING id, email, username, password, role, email_verified_at, deleted_at, erased_at
+            "#,
+        )
+        .bind(Utc::now() - Duration::days(RESTORE_DAYS))
+        .fetch_all(&self.pg)
+        .await?;
+        for user in &purged {
+            self.audit(
+                &Audit::default(),
+                "user.purge",
+                "user",
+                user.id,
+                Some(user),
+                None,
+            )
+            .await;
+        }
+        if !purged.is_empty() {
+            info!("{} deleted accounts purged", purged.len());
+        }
+        Ok(purged.len())
+    }
+}
+
+#[tokio::test]
+async fn deletion_t() {
+    use crate::products::model::NewProduct;
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let seller = state
+        .create_user(axum::Json(NewUser::test("leaving")))
+        .await
+        .unwrap();
+    let heir = state
+        .create_user(axum::Json(NewUser::test("heir")))
+        .await
+        .unwrap();
+    let product = state
+        .new_product(axum::Json(NewProduct {
+            name: "leftover".to_string(),
+            description: "stays for its buyers".to_string(),
+            price: 5,
+            owner_id: seller.id,
+            executable: None,
+        }))
+        .await
+        .unwrap();
+
+    let deleted = state
+        .deactivate_user(seller.username.clone(), &ProductPolicy::Unlist)
+        .await
+        .unwrap();
+    assert!(deleted.deleted_at.is_some());
+    assert!(state.get_user(seller.username.clone()).await.is_err());
+    let unlisted = state.get_product(product.id).await.unwrap();
+    assert_eq!(unlisted.unlisted_at, deleted.deleted_at);
+
+    state.restore_user(seller.username.clone()).await.unwrap();
+    assert!(
+        state
+            .get_product(product.id)
+            .await
+            .unwrap()
+            .unlisted_at
+            .is_none()
+    );
+
+    let policy = ProductPolicy::TransferTo(seller.username.clone());
+    assert!(
+        state
+            .deactivate_user(seller.username.clone(), &policy)
+            .await
+            .is_err()
+    );
+    let policy = ProductPolicy::TransferTo(heir.username.clone());
+    state
+        .deactivate_user(seller.username.clone(), &policy)
+        .await
+        .unwrap();
+    let moved = state.get_product(product.id).await.unwrap();
+    assert_eq!(moved.owner_id, Some(heir.id));
+    assert!(moved.unlisted_at.is_none());
+
+    // pretend the restore window is over
+    sqlx::query(r#"UPDATE "User" SET deleted_at = now() - interval '31 days' WHERE id = $1"#)
+        .bind(seller.id)
+        .execute(&state.pg)
+        .await
+        .unwrap();
+    assert!(state.purge_deleted_users().await.unwrap() >= 1);
+    assert!(state.deleted_user(seller.username).await.unwrap().is_none());
+
+    state.delete_product(product.id).await.unwrap();
+    state.delete_user(heir.username).await.unwrap();
+}
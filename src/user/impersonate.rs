@@ -0,0 +1,156 @@
+use crate::State;
+use crate::error::{Error, Result};
+use crate::user::Clains;
+use crate::user::model::Role;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use uuid::Uuid;
+
+/// The `act` claim (RFC 8693): who is really behind an impersonation token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImpersonationEvent {
+    pub id: i64,
+    pub impersonator: String,
+    pub target: String,
+    pub jti: Uuid,
+    pub event: String,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub status: Option<i16>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Clains {
+    /// Refuses impersonation tokens for actions only the account owner may take.
+    pub const fn forbid_impersonation(&self) -> Result<()> {
+        if self.act.is_some() {
+            return Err(Error::ImpersonationForbidden);
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    /// Mints a short-lived access token for `target` on behalf of `admin`, without a refresh token.
+    pub async fn impersonate(&self, admin: &Clains, target: String) -> Result<String> {
+        admin.forbid_impersonation()?;
+        let user = self.get_user(target).await?;
+        if user.role == Role::Admin {
+            return Err(Error::InvalidUser);
+        }
+        let mut claims = Clains::with_ttl(
+            user.username,
+            user.role,
+            self.config.tokens.impersonation_ttl_minutes,
+        )?;
+        claims.act = Some(Actor {
+            sub: admin.username.clone(),
+        });
+        query!(
+            "INSERT INTO impersonation_audit (impersonator, target, jti, event)
+            VALUES ($1, $2, $3, 'issued')",
+            admin.username,
+            claims.username,
+            claims.jti,
+        )
+        .execute(&self.pg)
+        .await?;
+        self.keys.sign(&claims)
+    }
+    pub async fn record_impersonated_request(
+        &self,
+        claims: &Clains,
+        method: &str,
+        path: &str,
+        status: u16,
+    ) -> Result<()> {
+        let Some(actor) = &claims.act else {
+            return Ok(());
+        };
+        query!(
+            "INSERT INTO impersonation_audit (impersonator, target, jti, event, method, path, status)
+            VALUES ($1, $2, $3, 'request', $4, $5, $6)",
+            actor.sub,
+            claims.username,
+            claims.jti,
+            method,
+            path,
+            i16::try_from(status)?,
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(())
+    }
+    /// Latest events, optionally only those concerning `target`.
+    pub async fn impersonation_events(
+        &self,
+        target: Option<String>,
+        limit: i64,
+    ) -> Result<Vec<ImpersonationEvent>> {
+        Ok(query_as!(
+            ImpersonationEvent,
+            "SELECT id, impersonator, target, jti, event, method, path, status, created_at
+            FROM impersonation_audit
+            WHERE $1::VARCHAR IS NULL OR target = $1
+            ORDER BY id DESC
+            LIMIT $2",
+            target,
+            limit,
+        )
+        .fetch_all(&self.pg)
+        .await?)
+    }
+}
+
+#[tokio::test]
+async fn impersonate_t() {
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let user = state
+        .create_user(axum::Json(NewUser::test("imperso")))
+        .await
+        .unwrap();
+    let admin = Clains::new("impadmin".to_string(), Role::Admin).unwrap();
+
+    let token = state
+        .impersonate(&admin, user.username.clone())
+        .await
+        .unwrap();
+    let claims = Clains::from_token(&token, &state.keys).unwrap();
+    assert_eq!(claims.username, user.username);
+    assert_eq!(claims.act.as_ref().unwrap().sub, "impadmin");
+    assert!(claims.sid.is_none());
+    assert!(claims.forbid_impersonation().is_err());
+    // impersonation doesn't chain
+    assert!(
+        state
+            .impersonate(&claims, user.username.clone())
+            .await
+            .is_err()
+    );
+
+    state
+        .record_impersonated_request(&claims, "GET", "/products", 200)
+        .await
+        .unwrap();
+    let events = state
+        .impersonation_events(Some(user.username.clone()), 10)
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].path.as_deref(), Some("/products"));
+    assert_eq!(events[1].event, "issued");
+
+    sqlx::query("DELETE FROM impersonation_audit WHERE target = $1")
+        .bind(&user.username)
+        .execute(&state.pg)
+        .await
+        .unwrap();
+    state.delete_user(user.username).await.unwrap();
+}
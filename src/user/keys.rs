@@ -0,0 +1,195 @@
+use crate::error::{Error, Result};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ed25519_dalek::SigningKey;
+use ed25519_dalek::pkcs8::{DecodePrivateKey, EncodePrivateKey, spki::der::pem::LineEnding};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header};
+use rand::rngs::OsRng;
+use serde::Serialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+/// Ed25519 signing keys kept as `<kid>.pem` (PKCS#8) in one directory.
+/// The `active` file names the key new tokens are signed with, every other key
+/// in the directory keeps verifying tokens until it is removed.
+#[derive(Clone)]
+pub struct KeyRing {
+    dir: PathBuf,
+    inner: Arc<RwLock<Keys>>,
+}
+struct Keys {
+    active: String,
+    keys: BTreeMap<String, Key>,
+}
+struct Key {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    x: String,
+}
+
+fn load_key(pem: &str) -> Result<(String, Key)> {
+    let signing = SigningKey::from_pkcs8_pem(pem).map_err(|_| Error::InvalidKey)?;
+    let public = signing.verifying_key().to_bytes();
+    let kid = hex::encode(&Sha256::digest(public)[..8]);
+    let key = Key {
+        encoding: EncodingKey::from_ed_pem(pem.as_bytes())?,
+        decoding: DecodingKey::from_ed_der(&public),
+        x: URL_SAFE_NO_PAD.encode(public),
+    };
+    Ok((kid, key))
+}
+
+fn write_private(path: &Path, pem: &str) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(pem.as_bytes())?;
+    }
+    #[cfg(not(unix))]
+    fs::write(path, pem)?;
+    Ok(())
+}
+
+fn read_dir(dir: &Path) -> Result<Keys> {
+    let mut keys = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "pem") {
+            let (kid, key) = load_key(&fs::read_to_string(&path)?)?;
+            keys.insert(kid, key);
+        }
+    }
+    let active = fs::read_to_string(dir.join("active"))
+        .map(|kid| kid.trim().to_string())
+        .unwrap_or_default();
+    if !keys.contains_key(&active) {
+        return Err(Error::InvalidKey);
+    }
+    Ok(Keys { active, keys })
+}
+
+impl KeyRing {
+    /// Loads the key directory, creating it and a first key when it is empty.
+    pub fn load(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        if !dir.join("active").exists() {
+            Self::generate(&dir)?;
+        }
+        let keys = read_dir(&dir)?;
+        Ok(Self {
+            dir,
+            inner: Arc::new(RwLock::new(keys)),
+        })
+    }
+    fn generate(dir: &Path) -> Result<String> {
+        let pem = SigningKey::generate(&mut OsRng)
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(|_| Error::InvalidKey)?;
+        let (kid, _) = load_key(&pem)?;
+        write_private(&dir.join(format!("{kid}.pem")), &pem)?;
+        fs::write(dir.join("active"), &kid)?;
+        info!("generated signing key {kid}");
+        Ok(kid)
+    }
+    /// Picks up keys added or removed by another instance sharing the directory.
+    pub fn reload(&self) -> Result<()> {
+        let keys = read_dir(&self.dir)?;
+        *self.inner.write().expect("key ring poisoned") = keys;
+        Ok(())
+    }
+    /// Signs with a new key from now on; tokens signed by older keys stay valid.
+    pub fn rotate(&self) -> Result<String> {
+        let kid = Self::generate(&self.dir)?;
+        self.reload()?;
+        Ok(kid)
+    }
+    /// Removes a retired key, every token it signed stops verifying.
+    pub fn remove(&self, kid: &str) -> Result<()> {
+        let inner = self.inner.read().expect("key ring poisoned");
+        if inner.active == kid || !inner.keys.contains_key(kid) {
+            return Err(Error::InvalidKey);
+        }
+        drop(inner);
+        fs::remove_file(self.dir.join(format!("{kid}.pem")))?;
+        self.reload()
+    }
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let inner = self.inner.read().expect("key ring poisoned");
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(inner.active.clone());
+        Ok(jsonwebtoken::encode(
+            &header,
+            claims,
+            &inner.keys[&inner.active].encoding,
+        )?)
+    }
+    pub fn decoding_key(&self, kid: &str) -> Result<DecodingKey> {
+        let inner = self.inner.read().expect("key ring poisoned");
+        inner
+            .keys
+            .get(kid)
+            .map(|key| key.decoding.clone())
+            .ok_or(Error::InvalidToken)
+    }
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<Value> = self
+            .inner
+            .read()
+            .expect("key ring poisoned")
+            .keys
+            .iter()
+            .map(|(kid, key)| {
+                json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "alg": "EdDSA",
+                    "use": "sig",
+                    "kid": kid,
+                    "x": key.x,
+                })
+            })
+            .collect();
+        json!({ "keys": keys })
+    }
+}
+
+#[test]
+fn keys_t() {
+    use crate::user::Clains;
+    use crate::user::model::Role;
+    let dir = std::env::temp_dir().join(format!("devmarket-keys-{}", uuid::Uuid::new_v4()));
+    let ring = KeyRing::load(&dir).unwrap();
+    let claims = Clains::new("keys".to_string(), Role::Seller).unwrap();
+    let old = ring.sign(&claims).unwrap();
+    let old_kid = jsonwebtoken::decode_header(&old).unwrap().kid.unwrap();
+
+    let new_kid = ring.rotate().unwrap();
+    assert_ne!(old_kid, new_kid);
+    let new = ring.sign(&claims).unwrap();
+    assert_eq!(
+        jsonwebtoken::decode_header(&new).unwrap().kid,
+        Some(new_kid.clone())
+    );
+    assert_eq!(ring.jwks()["keys"].as_array().unwrap().len(), 2);
+
+    // tokens signed before the rotation keep working until their key is removed
+    assert!(Clains::from_token(&old, &ring).is_ok());
+    assert!(Clains::from_token(&new, &ring).is_ok());
+    assert!(ring.remove(&new_kid).is_err());
+    ring.remove(&old_kid).unwrap();
+    assert!(Clains::from_token(&old, &ring).is_err());
+    assert!(Clains::from_token(&new, &ring).is_ok());
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,268 @@
+use crate::State;
+use crate::error::{Error, Result};
+use crate::user::model::{Role, User};
+use crate::user::refresh::hash_token;
+use crate::user::throttle::mfa_key;
+use chrono::Utc;
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, query, query_as, query_scalar};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const ISSUER: &str = "DevMarket";
+const STEP: u64 = 30;
+const RECOVERY_CODES: usize = 10;
+
+#[derive(Serialize)]
+pub struct Enrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct MfaPolicy {
+    pub role: Role,
+    pub required: bool,
+}
+
+fn totp(secret: &str, username: &str) -> Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|_| Error::InvalidMfaCode)?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        STEP,
+        secret,
+        Some(ISSUER.to_string()),
+        username.to_string(),
+    )
+    .map_err(|_| Error::InvalidMfaCode)
+}
+
+fn recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    OsRng.fill_bytes(&mut bytes);
+    let code = hex::encode(bytes);
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+impl State {
+    pub async fn mfa_enabled(&self, user_id: i32) -> Result<bool> {
+        Ok(query_scalar!(
+            "SELECT confirmed_at IS NOT NULL FROM user_mfa WHERE user_id = $1",
+            user_id
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        .flatten()
+        .unwrap_or(false))
+    }
+    pub async fn mfa_required(&self, role: &Role) -> Result<bool> {
+        Ok(
+            query_scalar::<_, bool>("SELECT required FROM role_mfa_policy WHERE role = $1")
+                .bind(role)
+                .fetch_optional(&self.pg)
+                .await?
+                .unwrap_or(false),
+        )
+    }
+    pub async fn mfa_policies(&self) -> Result<Vec<MfaPolicy>> {
+        Ok(
+            query_as::<_, MfaPolicy>("SELECT role, required FROM role_mfa_policy")
+                .fetch_all(&self.pg)
+                .await?,
+        )
+    }
+    pub async fn set_mfa_policy(&self, policy: &MfaPolicy) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO role_mfa_policy (role, required) VALUES ($1, $2)
+            ON CONFLICT (role) DO UPDATE SET required = EXCLUDED.required",
+        )
+        .bind(&policy.role)
+        .bind(policy.required)
+        .execute(&self.pg)
+        .await?;
+        Ok(())
+    }
+    /// Starts (or restarts) an enrollment, the secret is unused until `confirm_mfa`.
+    pub async fn enroll_mfa(&self, user: &User) -> Result<Enrollment> {
+        if self.mfa_enabled(user.id).await? {
+            return Err(Error::MfaAlreadyEnabled);
+        }
+        let mut bytes = [0u8; 20];
+        OsRng.fill_bytes(&mut bytes);
+        let Secret::Encoded(secret) = Secret::Raw(bytes.to_vec()).to_encoded() else {
+            unreachable!("to_encoded always returns an encoded secret")
+        };
+        query!(
+            "INSERT INTO user_mfa (user_id, secret) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET secret = EXCLUDED.secret, last_step = 0",
+            user.id,
+            secret,
+        )
+        .execute(&self.pg)
+        .await?;
+        let otpauth_uri = totp(&secret, &user.username)?.get_url();
+        Ok(Enrollment {
+            secret,
+            otpauth_uri,
+        })
+    }
+    /// Accepts each TOTP step at most once, one step of clock drift is tolerated.
+    async fn check_totp(&self, user: &User, code: &str, confirmed: bool) -> Result<()> {
+        let stored = query!(
+            "SELECT secret, confirmed_at FROM user_mfa WHERE user_id = $1",
+            user.id
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        .ok_or(Error::InvalidMfaCode)?;
+        if stored.confirmed_at.is_some() != confirmed {
+            return Err(Error::InvalidMfaCode);
+        }
+        let totp = totp(&stored.secret, &user.username)?;
+        let now = u64::try_from(Utc::now().timestamp()).map_err(|_| Error::InvalidMfaCode)? / STEP;
+        let step = (now - 1..=now + 1)
+            .find(|step| totp.check(code, step * STEP))
+            .ok_or(Error::InvalidMfaCode)?;
+        let accepted = query!(
+            "UPDATE user_mfa SET last_step = $2 WHERE user_id = $1 AND last_step < $2",
+            user.id,
+            i64::try_from(step).map_err(|_| Error::InvalidMfaCode)?,
+        )
+        .execute(&self.pg)
+        .await?;
+        if accepted.rows_affected() == 0 {
+            return Err(Error::InvalidMfaCode);
+        }
+        Ok(())
+    }
+    async fn replace_recovery_codes(&self, user_id: i32) -> Result<Vec<String>> {
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| recovery_code()).collect();
+        let hashes: Vec<String> = codes.iter().map(|code| hash_token(code)).collect();
+        let mut tx = self.pg.begin().await?;
+        query!("DELETE FROM mfa_recovery_code WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
+        query!(
+            "INSERT INTO mfa_recovery_code (user_id, code_hash)
+            SELECT $1, * FROM UNNEST($2::VARCHAR[])",
+            user_id,
+            &hashes,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(codes)
+    }
+    /// Activates a pending enrollment and returns fresh recovery codes.
+    pub async fn confirm_mfa(&self, user: &User, code: &str) -> Result<Vec<String>> {
+        self.check_totp(user, code, false).await?;
+        query!(
+            "UPDATE user_mfa SET confirmed_at = now() WHERE user_id = $1",
+            user.id
+        )
+        .execute(&self.pg)
+        .await?;
+        self.replace_recovery_codes(user.id).await
+    }
+    /// Checks a TOTP code, or burns a recovery code when `code` has the `xxxxx-xxxxx` form.
+    /// Failed attempts lock the second factor like failed passwords lock the account.
+    pub async fn check_mfa(&self, user: &User, code: &str) -> Result<()> {
+        let key = mfa_key(&user.username);
+        self.ensure_not_locked(std::slice::from_ref(&key)).await?;
+        match self.check_code(user, code).await {
+            Ok(()) => {
+                self.clear_login_failures(&key).await?;
+                Ok(())
+            }
+            Err(Error::InvalidMfaCode) => {
+                self.record_mfa_failure(&user.username).await?;
+                Err(Error::InvalidMfaCode)
+            }
+            Err(e) => Err(e),
+        }
+    }
+    async fn check_code(&self, user: &User, code: &str) -> Result<()> {
+        if !code.contains('-') {
+            return self.check_totp(user, code, true).await;
+        }
+        query!(
+            "UPDATE mfa_recovery_code SET used_at = now()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            RETURNING id",
+            user.id,
+            hash_token(code),
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        .ok_or(Error::InvalidMfaCode)?;
+        Ok(())
+    }
+    pub async fn regenerate_recovery_codes(&self, user: &User, code: &str) -> Result<Vec<String>> {
+        self.check_mfa(user, code).await?;
+        self.replace_recovery_codes(user.id).await
+    }
+    pub async fn disable_mfa(&self, user: &User, code: &str) -> Result<()> {
+        if self.mfa_required(&user.role).await? {
+            return Err(Error::MfaRequired);
+        }
+        self.check_mfa(user, code).await?;
+        query!("DELETE FROM user_mfa WHERE user_id = $1", user.id)
+            .execute(&self.pg)
+            .await?;
+        query!("DELETE FROM mfa_recovery_code WHERE user_id = $1", user.id)
+            .execute(&self.pg)
+            .await?;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn mfa_t() {
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let user = state
+        .create_user(axum::Json(NewUser::test("mfa")))
+        .await
+        .unwrap();
+
+    let enrollment = state.enroll_mfa(&user).await.unwrap();
+    assert!(
+        enrollment
+            .otpauth_uri
+            .starts_with(&format!("otpauth://totp/DevMarket:{}", user.username))
+    );
+    let totp = totp(&enrollment.secret, &user.username).unwrap();
+    let code = totp.generate_current().unwrap();
+    assert!(state.check_mfa(&user, &code).await.is_err());
+
+    let recovery = state.confirm_mfa(&user, &code).await.unwrap();
+    assert_eq!(recovery.len(), RECOVERY_CODES);
+    assert!(state.mfa_enabled(user.id).await.unwrap());
+    assert!(state.enroll_mfa(&user).await.is_err());
+    // the same step can't be replayed
+    assert!(state.check_mfa(&user, &code).await.is_err());
+
+    state.check_mfa(&user, &recovery[0]).await.unwrap();
+    assert!(state.check_mfa(&user, &recovery[0]).await.is_err());
+    state.disable_mfa(&user, &recovery[1]).await.unwrap();
+    assert!(!state.mfa_enabled(user.id).await.unwrap());
+
+    for _ in 0..6 {
+        assert!(state.check_mfa(&user, "000000").await.is_err());
+    }
+    assert!(matches!(
+        state.check_mfa(&user, "000000").await,
+        Err(Error::TooManyAttempts(_))
+    ));
+    assert!(
+        state
+            .clear_login_failures(&mfa_key(&user.username))
+            .await
+            .unwrap()
+    );
+
+    state.delete_user(user.username).await.unwrap();
+}
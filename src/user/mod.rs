@@ -1,31 +1,121 @@
+use crate::audit::{Audit, AuditEntry, AuditFilter};
 use crate::error::{Error, Result};
-use crate::ext::IsAuth;
+use crate::ext::{
+    Device, IsAuth, JsonBody, MfaPending, MfaSubject, Path, Query, ValidatedJson, interactive_only,
+};
+use crate::policy::{
+    ApiKeyCreate, AuditRead, Impersonate, LockoutManage, MfaPolicyManage, Require, SessionManage,
+    SigningKeyManage, UserManage, require_account_owner, require_outranks,
+};
+use crate::user::api_key::{ApiKey, CreatedApiKey, NewApiKey, Scope};
+use crate::user::deletion::ProductPolicy;
+use crate::user::impersonate::ImpersonationEvent;
+use crate::user::keys::KeyRing;
+use crate::user::mfa::{Enrollment, MfaPolicy};
 use crate::user::model::Role;
+use crate::user::oidc::{Identity, Outcome, cookie_nonce, nonce_cookie};
+use crate::user::refresh::random_token;
+use crate::user::session::Session;
+use crate::user::throttle::{Lockout, account_key, ip_key};
 use crate::{
     State as Mc,
-    user::model::{NewUser, User},
+    user::model::{NewUser, User, password_strength},
 };
-use axum::response::IntoResponse;
+use axum::extract::ConnectInfo;
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware;
+use axum::response::{IntoResponse, Redirect};
 use axum::routing::get;
 use axum::{
     Json, Router,
-    extract::{Path, State},
+    extract::State,
     routing::{delete, post, put},
 };
 use bcrypt::verify;
 use chrono::{Duration, Utc};
-use jsonwebtoken::{
-    Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation, decode, encode,
-};
+use jsonwebtoken::{Algorithm, TokenData, Validation, decode, decode_header};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tracing::{debug, info};
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+use validator::Validate;
+pub mod api_key;
+pub mod deletion;
+pub mod impersonate;
+pub mod keys;
+pub mod mfa;
 pub mod model;
+pub mod oidc;
+pub mod privacy;
+pub mod refresh;
+pub mod reset;
+pub mod revocation;
+pub mod session;
+pub mod throttle;
+pub mod verify;
 #[derive(Deserialize, Serialize)]
 struct LgForm {
     username: String,
     password: String,
 }
+#[derive(Deserialize, Default)]
+struct DeleteForm {
+    #[serde(default)]
+    products: ProductPolicy,
+}
+#[derive(Deserialize)]
+struct RefreshForm {
+    refresh_token: String,
+}
+#[derive(Deserialize)]
+struct VerifyForm {
+    token: String,
+}
+#[derive(Deserialize)]
+struct ForgotForm {
+    email: String,
+}
+#[derive(Deserialize, Validate)]
+struct ResetForm {
+    token: String,
+    #[validate(
+        length(min = 8, max = 72, message = "must be 8 to 72 characters"),
+        custom(function = "password_strength")
+    )]
+    password: String,
+}
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: String,
+    state: String,
+}
+#[derive(Deserialize)]
+struct EventQuery {
+    target: Option<String>,
+    limit: Option<i64>,
+}
+#[derive(Deserialize)]
+struct PasswordForm {
+    password: String,
+}
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Json,
+    Zip,
+}
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+#[derive(Deserialize)]
+struct CodeForm {
+    code: String,
+}
 #[derive(Deserialize, Serialize)]
 struct NewUserResp {
     user: User,
@@ -34,59 +124,257 @@ struct NewUserResp {
 
 pub fn user_router() -> Router<Mc> {
     Router::new()
+        .route("/api-keys", get(api_keys).post(create_api_key))
+        .route("/api-keys/:id", delete(revoke_api_key))
         .route("/register", post(create_user))
         .route("/update/:username", put(update_user))
-        .route("/delete", delete(delete_user))
+        .route("/delete/:username", delete(delete_user))
+        .route("/restore", post(restore_account))
+        .route("/me/export", get(export_data))
+        .route("/me/erase", post(erase_account))
         .route("/login", post(login))
+        .route("/oidc/:provider/login", get(oidc_login))
+        .route("/oidc/:provider/link", post(oidc_link))
+        .route("/oidc/:provider/callback", get(oidc_callback))
+        .route("/identities", get(identities))
+        .route("/identities/:provider", delete(unlink_identity))
+        .route("/refresh", post(refresh))
+        .route("/logout", post(logout))
+        .route("/sessions", get(sessions).delete(revoke_sessions))
+        .route("/sessions/:id", delete(revoke_session))
+        .route("/verify-email", post(verify_email))
+        .route("/verify-email/resend", post(resend_verification))
+        .route("/password/forgot", post(forgot_password))
+        .route("/password/reset", post(reset_password))
+        .route("/mfa", delete(disable_mfa))
+        .route("/mfa/enroll", post(enroll_mfa))
+        .route("/mfa/confirm", post(confirm_mfa))
+        .route("/mfa/verify", post(verify_mfa))
+        .route("/mfa/recovery-codes", post(regenerate_recovery_codes))
+        .route("/admin/mfa-policy", get(mfa_policies).put(set_mfa_policy))
+        .route("/admin/lockouts", get(lockouts))
+        .route("/admin/lockouts/:key", delete(clear_lockout))
+        .route("/admin/logout/:username", post(force_logout))
+        .route(
+            "/admin/users/:username/sessions",
+            get(user_sessions).delete(revoke_user_sessions),
+        )
+        .route(
+            "/admin/users/:username/sessions/:id",
+            delete(revoke_user_session),
+        )
+        .route("/admin/impersonate/:username", post(impersonate))
+        .route("/admin/impersonations", get(impersonations))
+        .route("/admin/users/:username/restore", post(admin_restore_user))
+        .route("/admin/users/:username/erase", post(admin_erase_user))
+        .route("/admin/audit", get(audit_log))
+        .route("/admin/keys/rotate", post(rotate_keys))
+        .route("/admin/keys/:kid", delete(remove_key))
         .route("/:username", get(get_user))
+        .route_layer(middleware::from_fn(interactive_only))
 }
 
-async fn create_user(State(mc): State<Mc>, data: Json<NewUser>) -> Result<impl IntoResponse> {
+async fn create_user(
+    device: Device,
+    audit: Audit,
+    State(mc): State<Mc>,
+    ValidatedJson(data): ValidatedJson<NewUser>,
+) -> Result<impl IntoResponse> {
     info!("creating user started");
-    let data = mc.create_user(data).await?;
+    let data = mc.create_user(Json(data)).await?;
+    let audit = Audit {
+        actor: Some(data.username.clone()),
+        ..audit
+    };
+    mc.audit(&audit, "user.create", "user", data.id, None, Some(&data))
+        .await;
     info!("creating user has been finished");
+    if let Err(e) = mc.send_verification_email(&data).await {
+        warn!("failed to send the verification email: {e}");
+    }
     info!("login started");
-    let login_data = Json(LgForm {
-        username: data.username.clone(),
-        password: data.password.clone(),
-    });
-    let Json(value) = login(State(mc), login_data).await?;
-    let login_info: LgForm = serde_json::from_value(value)?;
+    // a role that requires a second factor gets an enrollment token, not a session
+    let Json(value) = complete_login(&mc, data.clone(), &device).await?;
 
     let response = NewUserResp {
         user: data,
-        message: serde_json::to_value(login_info)?,
+        message: value,
     };
     info!("login went successfuly");
     Ok(Json(response))
 }
 async fn update_user(
     IsAuth(ext): IsAuth,
+    audit: Audit,
     State(mc): State<Mc>,
     Path(username): Path<String>,
-    data: Json<NewUser>,
+    ValidatedJson(data): ValidatedJson<NewUser>,
 ) -> Result<Json<User>> {
-    if ext.username == username || ext.role == Role::Admin {
-        info!("updating user started");
-        let data = mc.update_user(data, username).await?;
-        info!("updating user has been finished");
-        return Ok(Json(data));
-    }
-    Err(Error::InvalidUser)
+    ext.forbid_impersonation()?;
+    let before = mc.get_user(username.clone()).await?;
+    require_account_owner(&ext, &username, &before.role)?;
+    info!("updating user started");
+    let data = mc.update_user(Json(data), username).await?;
+    mc.audit(
+        &audit.by(&ext),
+        "user.update",
+        "user",
+        data.id,
+        Some(&before),
+        Some(&data),
+    )
+    .await;
+    info!("updating user has been finished");
+    Ok(Json(data))
 }
 async fn delete_user(
     IsAuth(ext): IsAuth,
+    audit: Audit,
     State(mc): State<Mc>,
     Path(username): Path<String>,
+    form: Option<JsonBody<DeleteForm>>,
 ) -> Result<Json<User>> {
-    if username == ext.username || ext.role == Role::Admin {
-        info!("deleting user started");
-        let data = mc.delete_user(username).await?;
-        info!("deleting user finished");
-        return Ok(Json(data));
+    ext.forbid_impersonation()?;
+    let target = mc.get_user(username.clone()).await?;
+    require_account_owner(&ext, &username, &target.role)?;
+    info!("deleting user started");
+    let form = form.map(|JsonBody(form)| form).unwrap_or_default();
+    let data = mc.deactivate_user(username, &form.products).await?;
+    let before = User {
+        deleted_at: None,
+        ..data.clone()
+    };
+    mc.audit(
+        &audit.by(&ext),
+        "user.delete",
+        "user",
+        data.id,
+        Some(&before),
+        Some(&data),
+    )
+    .await;
+    info!("deleting user finished");
+    Ok(Json(data))
+}
+/// Lets the owner of a deleted account take it back during the restore window.
+async fn restore_account(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    device: Device,
+    audit: Audit,
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<LgForm>,
+) -> Result<Json<Value>> {
+    mc.ensure_not_locked(&[account_key(&form.username), ip_key(addr.ip())])
+        .await?;
+    if let Some(user) = mc.deleted_user(form.username.clone()).await?
+        && verify(&form.password, &user.password)?
+    {
+        let restored = mc.restore_user(user.username.clone()).await?;
+        mc.audit(
+            &audit.by_user(&restored),
+            "user.restore",
+            "user",
+            restored.id,
+            Some(&user),
+            Some(&restored),
+        )
+        .await;
+        info!("account restored");
+        return complete_login(&mc, restored, &device).await;
     }
+    mc.record_login_failure(&form.username, addr.ip()).await?;
     Err(Error::InvalidUser)
 }
+async fn admin_restore_user(
+    Require(ext, _): Require<UserManage>,
+    audit: Audit,
+    State(mc): State<Mc>,
+    Path(username): Path<String>,
+) -> Result<Json<User>> {
+    let before = mc
+        .deleted_user(username.clone())
+        .await?
+        .ok_or(Error::InvalidUser)?;
+    require_outranks(&ext, &before.role)?;
+    let restored = mc.restore_user(username).await?;
+    mc.audit(
+        &audit.by(&ext),
+        "user.restore",
+        "user",
+        restored.id,
+        Some(&before),
+        Some(&restored),
+    )
+    .await;
+    info!("{} restored {}", ext.username, restored.username);
+    Ok(Json(restored))
+}
+/// Everything stored about the caller, as JSON or as a ZIP archive with the uploads.
+async fn export_data(
+    IsAuth(ext): IsAuth,
+    State(mc): State<Mc>,
+    Query(query): Query<ExportQuery>,
+) -> Result<impl IntoResponse> {
+    info!("exporting user data started");
+    let user = mc.get_user(ext.username).await?;
+    let name = format!("devmarket-{}", user.username);
+    let export = mc.export_user(user).await?;
+    let (content_type, extension, body) = match query.format {
+        ExportFormat::Json => (
+            "application/json",
+            "json",
+            serde_json::to_vec_pretty(&export)?,
+        ),
+        ExportFormat::Zip => ("application/zip", "zip", export.into_zip(&*mc.blobs).await?),
+    };
+    info!("exporting user data finished");
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{name}.{extension}\""),
+            ),
+        ],
+        body,
+    ))
+}
+/// Anonymizes the caller's account for good, after checking the password again.
+async fn erase_account(
+    IsAuth(ext): IsAuth,
+    audit: Audit,
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<PasswordForm>,
+) -> Result<StatusCode> {
+    ext.forbid_impersonation()?;
+    let user = mc.get_user(ext.username.clone()).await?;
+    if !verify(&form.password, &user.password)? {
+        return Err(Error::InvalidUser);
+    }
+    mc.erase_user(&user).await?;
+    // no snapshot, the log must not keep what was just erased
+    mc.audit::<User>(&audit.by(&ext), "user.erase", "user", user.id, None, None)
+        .await;
+    info!("account erased");
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn admin_erase_user(
+    Require(ext, _): Require<UserManage>,
+    audit: Audit,
+    State(mc): State<Mc>,
+    Path(username): Path<String>,
+) -> Result<StatusCode> {
+    let user = match mc.get_user(username.clone()).await {
+        Ok(user) => user,
+        Err(_) => mc.deleted_user(username).await?.ok_or(Error::InvalidUser)?,
+    };
+    require_outranks(&ext, &user.role)?;
+    mc.erase_user(&user).await?;
+    mc.audit::<User>(&audit.by(&ext), "user.erase", "user", user.id, None, None)
+        .await;
+    info!("{} erased user {}", ext.username, user.id);
+    Ok(StatusCode::NO_CONTENT)
+}
 async fn get_user(State(mc): State<Mc>, Path(username): Path<String>) -> Result<Json<User>> {
     info!("fetching user started");
     let data = mc.get_user(username).await?;
@@ -98,12 +386,43 @@ pub struct Clains {
     pub username: String,
     pub role: Role,
     pub exp: usize,
+    pub iat: usize,
+    /// `iat` in milliseconds, so a user revocation in the same second still catches the token.
+    #[serde(default)]
+    pub iat_ms: i64,
+    pub jti: Uuid,
+    /// Password was checked but the second factor is still missing.
+    #[serde(default)]
+    pub mfa_pending: bool,
+    /// Session the token was issued for, absent for API keys and pending logins.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<Uuid>,
+    /// The admin behind an impersonation token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<impersonate::Actor>,
+    /// Only set when authenticated with an API key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<Scope>>,
 }
 impl Clains {
-    fn new(username: String, role: Role) -> Result<Self> {
+    /// An access token with the default lifetime.
+    #[cfg(test)]
+    pub fn new(username: String, role: Role) -> Result<Self> {
+        Self::with_ttl(
+            username,
+            role,
+            crate::config::TokenConfig::default().access_ttl_minutes,
+        )
+    }
+    fn pending_mfa(username: String, role: Role, minutes: i64) -> Result<Self> {
+        let mut data = Self::with_ttl(username, role, minutes)?;
+        data.mfa_pending = true;
+        Ok(data)
+    }
+    fn with_ttl(username: String, role: Role, minutes: i64) -> Result<Self> {
+        let now = Utc::now();
         let now_t = usize::try_from(
-            Utc::now()
-                .checked_add_signed(Duration::minutes(60))
+            now.checked_add_signed(Duration::minutes(minutes))
                 .expect("invalide time stamp")
                 .timestamp(),
         );
@@ -111,39 +430,473 @@ impl Clains {
             username,
             role,
             exp: now_t?,
+            iat: usize::try_from(now.timestamp())?,
+            iat_ms: now.timestamp_millis(),
+            jti: Uuid::new_v4(),
+            mfa_pending: false,
+            sid: None,
+            act: None,
+            scopes: None,
         };
         Ok(data)
     }
-    pub fn from_token(token: &str) -> Result<Self> {
-        let secret = std::env::var("JWT_SECRET")?;
-
-        let validation = Validation::new(Algorithm::HS256);
+    pub fn require_scope(&self, scope: Scope) -> Result<()> {
+        if self
+            .scopes
+            .as_ref()
+            .is_some_and(|scopes| !scopes.contains(&scope))
+        {
+            return Err(Error::MissingScope);
+        }
+        Ok(())
+    }
+    pub fn from_token(token: &str, keys: &KeyRing) -> Result<Self> {
+        let kid = decode_header(token)?.kid.ok_or(Error::InvalidToken)?;
+        let validation = Validation::new(Algorithm::EdDSA);
 
-        let token_data: TokenData<Self> = decode::<Self>(
-            token,
-            &DecodingKey::from_secret(secret.as_bytes()),
-            &validation,
-        )?;
+        let token_data: TokenData<Self> =
+            decode::<Self>(token, &keys.decoding_key(&kid)?, &validation)?;
 
         // Return the claims
         Ok(token_data.claims)
     }
 }
-#[axum::debug_handler]
-async fn login(State(mc): State<Mc>, Json(form): Json<LgForm>) -> Result<Json<Value>> {
+fn sign_access_token(mc: &Mc, username: String, role: Role, sid: Uuid) -> Result<String> {
+    let mut claims = Clains::with_ttl(username, role, mc.config.tokens.access_ttl_minutes)?;
+    claims.sid = Some(sid);
+    mc.keys.sign(&claims)
+}
+async fn start_session(mc: &Mc, user: User, device: &Device) -> Result<Json<Value>> {
+    let sid = mc.create_session(user.id, device).await?;
+    let refresh_token = mc.issue_refresh_token(user.id, sid).await?;
+    let jwt = sign_access_token(mc, user.username, user.role, sid)?;
+    Ok(token_body(mc, &jwt, &refresh_token))
+}
+fn token_body(mc: &Mc, access_token: &str, refresh_token: &str) -> Json<Value> {
+    Json(serde_json::json!({
+        "access_token": access_token,
+        "refresh_token": refresh_token,
+        "token_type": "Bearer",
+        "expires_in": mc.config.tokens.access_ttl_minutes * 60,
+    }))
+}
+/// Hands out a session, or an `mfa_token` when the user still needs a second factor.
+async fn complete_login(mc: &Mc, user: User, device: &Device) -> Result<Json<Value>> {
+    let enrolled = mc.mfa_enabled(user.id).await?;
+    if enrolled || mc.mfa_required(&user.role).await? {
+        let pending = mc.keys.sign(&Clains::pending_mfa(
+            user.username,
+            user.role,
+            mc.config.tokens.mfa_ttl_minutes,
+        )?)?;
+        info!("first factor accepted, waiting for the second factor");
+        return Ok(Json(serde_json::json!({
+            "mfa_token": pending,
+            "enrollment_required": !enrolled,
+            "expires_in": mc.config.tokens.mfa_ttl_minutes * 60,
+        })));
+    }
+    start_session(mc, user, device).await
+}
+/// What `login` checks passwords of unknown users against.
+static UNKNOWN_USER_HASH: LazyLock<String> = LazyLock::new(|| {
+    bcrypt::hash(random_token(), bcrypt::DEFAULT_COST).expect("bcrypt hash of a random token")
+});
+// the checks it generates trip the pedantic lints
+#[cfg_attr(not(clippy), axum::debug_handler)]
+async fn login(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    device: Device,
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<LgForm>,
+) -> Result<Json<Value>> {
     info!("starting user login");
-    let user: User = mc.get_user(form.username.clone()).await?;
+    mc.ensure_not_locked(&[account_key(&form.username), ip_key(addr.ip())])
+        .await?;
+    let user = mc.get_user(form.username.clone()).await.ok();
     info!("username has been fetched");
-    if verify(form.password, &user.password)? {
-        let newt = Clains::new(form.username.clone(), user.role)?;
-        let jwt = encode(
-            &Header::default(),
-            &newt,
-            &EncodingKey::from_secret(mc.jwt_secret.as_bytes()),
-        )?;
+    // unknown users cost a bcrypt round too, so timing doesn't tell which accounts exist
+    let stored = user
+        .as_ref()
+        .map_or(UNKNOWN_USER_HASH.as_str(), |user| user.password.as_str());
+    let matches = verify(&form.password, stored)?;
+    if let Some(user) = user
+        && matches
+    {
+        mc.clear_login_failures(&account_key(&user.username))
+            .await?;
+        let tokens = complete_login(&mc, user, &device).await?;
         info!("ur successfuly loged in");
-        return Ok(Json(serde_json::json!({ "access_token": jwt })));
+        return Ok(tokens);
     }
     debug!("sthg went wrong when loging in");
+    mc.record_login_failure(&form.username, addr.ip()).await?;
     Err(Error::InvalidUser)
 }
+async fn oidc_login(
+    State(mc): State<Mc>,
+    Path(provider): Path<String>,
+) -> Result<impl IntoResponse> {
+    info!("oidc login with {provider} started");
+    let (url, nonce) = mc.begin_oidc(&provider, None).await?;
+    Ok((
+        [(header::SET_COOKIE, nonce_cookie(Some(&nonce)))],
+        Redirect::to(&url),
+    ))
+}
+async fn oidc_link(
+    IsAuth(ext): IsAuth,
+    State(mc): State<Mc>,
+    Path(provider): Path<String>,
+) -> Result<impl IntoResponse> {
+    ext.forbid_impersonation()?;
+    let user = mc.get_user(ext.username).await?;
+    info!("linking {provider} started");
+    let (url, nonce) = mc.begin_oidc(&provider, Some(user.id)).await?;
+    Ok((
+        [(header::SET_COOKIE, nonce_cookie(Some(&nonce)))],
+        Json(serde_json::json!({ "authorization_url": url })),
+    ))
+}
+async fn oidc_callback(
+    device: Device,
+    State(mc): State<Mc>,
+    Path(provider): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Result<impl IntoResponse> {
+    let nonce = headers
+        .get(header::COOKIE)
+        .and_then(|cookie| cookie.to_str().ok())
+        .and_then(cookie_nonce)
+        .ok_or(Error::InvalidToken)?;
+    let outcome = mc
+        .finish_oidc(&provider, &query.code, &query.state, nonce)
+        .await?;
+    let body = match outcome {
+        Outcome::Login(user) => {
+            info!("oidc login with {provider} finished");
+            complete_login(&mc, user, &device).await?
+        }
+        Outcome::Linked(identity) => {
+            info!("{provider} linked");
+            Json(serde_json::to_value(identity)?)
+        }
+    };
+    Ok(([(header::SET_COOKIE, nonce_cookie(None))], body))
+}
+async fn identities(IsAuth(ext): IsAuth, State(mc): State<Mc>) -> Result<Json<Vec<Identity>>> {
+    let user = mc.get_user(ext.username).await?;
+    Ok(Json(mc.identities(user.id).await?))
+}
+async fn unlink_identity(
+    IsAuth(ext): IsAuth,
+    State(mc): State<Mc>,
+    Path(provider): Path<String>,
+) -> Result<StatusCode> {
+    ext.forbid_impersonation()?;
+    let user = mc.get_user(ext.username).await?;
+    info!("unlinking {provider}");
+    if mc.unlink_identity(user.id, &provider).await? {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    Ok(StatusCode::NOT_FOUND)
+}
+async fn refresh(
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<RefreshForm>,
+) -> Result<Json<Value>> {
+    info!("refreshing token started");
+    let (user_id, family, refresh_token) = mc.rotate_refresh_token(&form.refresh_token).await?;
+    let user = mc.get_user_by_id(user_id).await?;
+    mc.touch_session(family);
+    let jwt = sign_access_token(&mc, user.username, user.role, family)?;
+    info!("token of family {family} refreshed");
+    Ok(token_body(&mc, &jwt, &refresh_token))
+}
+async fn logout(
+    auth: Option<IsAuth>,
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<RefreshForm>,
+) -> Result<StatusCode> {
+    info!("logout started");
+    let family = mc.revoke_refresh_family(&form.refresh_token).await?;
+    if let Some(IsAuth(ext)) = auth {
+        mc.revoke_token(&ext).await?;
+    }
+    info!("refresh family {family} revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn sessions(IsAuth(ext): IsAuth, State(mc): State<Mc>) -> Result<Json<Vec<Session>>> {
+    let user = mc.get_user(ext.username).await?;
+    Ok(Json(mc.sessions(user.id, ext.sid).await?))
+}
+async fn revoke_sessions(IsAuth(ext): IsAuth, State(mc): State<Mc>) -> Result<StatusCode> {
+    ext.forbid_impersonation()?;
+    let user = mc.get_user(ext.username).await?;
+    let count = mc.revoke_sessions(user.id, None).await?;
+    info!("{count} sessions revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn revoke_session(
+    IsAuth(ext): IsAuth,
+    State(mc): State<Mc>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    ext.forbid_impersonation()?;
+    let user = mc.get_user(ext.username).await?;
+    info!("revoking session {id}");
+    if mc.revoke_sessions(user.id, Some(id)).await? > 0 {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    Ok(StatusCode::NOT_FOUND)
+}
+async fn verify_email(
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<VerifyForm>,
+) -> Result<StatusCode> {
+    info!("email verification started");
+    mc.verify_email(&form.token).await?;
+    info!("email verified");
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn resend_verification(IsAuth(ext): IsAuth, State(mc): State<Mc>) -> Result<StatusCode> {
+    let user = mc.get_user(ext.username).await?;
+    mc.send_verification_email(&user).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+async fn forgot_password(
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<ForgotForm>,
+) -> Result<StatusCode> {
+    info!("password reset requested");
+    mc.request_password_reset(form.email).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+async fn reset_password(
+    State(mc): State<Mc>,
+    ValidatedJson(form): ValidatedJson<ResetForm>,
+) -> Result<StatusCode> {
+    info!("password reset started");
+    mc.reset_password(&form.token, &form.password).await?;
+    info!("password reset finished");
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn enroll_mfa(MfaSubject(ext): MfaSubject, State(mc): State<Mc>) -> Result<Json<Enrollment>> {
+    ext.forbid_impersonation()?;
+    info!("mfa enrollment started");
+    let user = mc.get_user(ext.username).await?;
+    Ok(Json(mc.enroll_mfa(&user).await?))
+}
+async fn confirm_mfa(
+    MfaSubject(ext): MfaSubject,
+    device: Device,
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<CodeForm>,
+) -> Result<Json<Value>> {
+    ext.forbid_impersonation()?;
+    let user = mc.get_user(ext.username.clone()).await?;
+    let codes = mc.confirm_mfa(&user, &form.code).await?;
+    info!("mfa enrollment confirmed");
+    let mut body = serde_json::json!({ "recovery_codes": codes });
+    if ext.mfa_pending {
+        // enrollment was forced at login, finish the login as well
+        mc.revoke_token(&ext).await?;
+        let Json(tokens) = start_session(&mc, user, &device).await?;
+        body["tokens"] = tokens;
+    }
+    Ok(Json(body))
+}
+async fn verify_mfa(
+    MfaPending(ext): MfaPending,
+    device: Device,
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<CodeForm>,
+) -> Result<Json<Value>> {
+    info!("mfa verification started");
+    let user = mc.get_user(ext.username.clone()).await?;
+    mc.check_mfa(&user, &form.code).await?;
+    mc.revoke_token(&ext).await?;
+    let tokens = start_session(&mc, user, &device).await?;
+    info!("ur successfuly loged in");
+    Ok(tokens)
+}
+async fn regenerate_recovery_codes(
+    IsAuth(ext): IsAuth,
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<CodeForm>,
+) -> Result<Json<Value>> {
+    ext.forbid_impersonation()?;
+    let user = mc.get_user(ext.username).await?;
+    let codes = mc.regenerate_recovery_codes(&user, &form.code).await?;
+    Ok(Json(serde_json::json!({ "recovery_codes": codes })))
+}
+async fn disable_mfa(
+    IsAuth(ext): IsAuth,
+    State(mc): State<Mc>,
+    JsonBody(form): JsonBody<CodeForm>,
+) -> Result<StatusCode> {
+    ext.forbid_impersonation()?;
+    info!("disabling mfa started");
+    let user = mc.get_user(ext.username).await?;
+    mc.disable_mfa(&user, &form.code).await?;
+    info!("mfa disabled");
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn mfa_policies(
+    _: Require<MfaPolicyManage>,
+    State(mc): State<Mc>,
+) -> Result<Json<Vec<MfaPolicy>>> {
+    Ok(Json(mc.mfa_policies().await?))
+}
+async fn set_mfa_policy(
+    _: Require<MfaPolicyManage>,
+    State(mc): State<Mc>,
+    JsonBody(policy): JsonBody<MfaPolicy>,
+) -> Result<StatusCode> {
+    info!("setting mfa policy of {:?}", policy.role);
+    mc.set_mfa_policy(&policy).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn create_api_key(
+    Require(ext, _): Require<ApiKeyCreate>,
+    State(mc): State<Mc>,
+    ValidatedJson(data): ValidatedJson<NewApiKey>,
+) -> Result<Json<CreatedApiKey>> {
+    ext.forbid_impersonation()?;
+    let user = mc.get_user(ext.username).await?;
+    user.ensure_verified()?;
+    info!("creating api key started");
+    let data = mc.create_api_key(user.id, data).await?;
+    info!("api key {} created", data.api_key.prefix);
+    Ok(Json(data))
+}
+async fn api_keys(IsAuth(ext): IsAuth, State(mc): State<Mc>) -> Result<Json<Vec<ApiKey>>> {
+    let user = mc.get_user(ext.username).await?;
+    Ok(Json(mc.api_keys(user.id).await?))
+}
+async fn revoke_api_key(
+    IsAuth(ext): IsAuth,
+    State(mc): State<Mc>,
+    Path(id): Path<i64>,
+) -> Result<Json<ApiKey>> {
+    ext.forbid_impersonation()?;
+    let user = mc.get_user(ext.username).await?;
+    info!("revoking api key {id}");
+    Ok(Json(mc.revoke_api_key(user.id, id).await?))
+}
+async fn lockouts(_: Require<LockoutManage>, State(mc): State<Mc>) -> Result<Json<Vec<Lockout>>> {
+    Ok(Json(mc.lockouts().await?))
+}
+async fn clear_lockout(
+    Require(ext, _): Require<LockoutManage>,
+    State(mc): State<Mc>,
+    Path(key): Path<String>,
+) -> Result<StatusCode> {
+    let username = key
+        .strip_prefix("user:")
+        .or_else(|| key.strip_prefix("mfa:"));
+    if let Some(username) = username
+        && let Ok(user) = mc.get_user(username.to_string()).await
+    {
+        require_outranks(&ext, &user.role)?;
+    }
+    info!("clearing lockout of {key}");
+    if mc.clear_login_failures(&key).await? {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    Ok(StatusCode::NOT_FOUND)
+}
+async fn force_logout(
+    Require(ext, _): Require<SessionManage>,
+    State(mc): State<Mc>,
+    Path(username): Path<String>,
+) -> Result<StatusCode> {
+    info!("force logout started");
+    let user = mc.get_user(username).await?;
+    require_outranks(&ext, &user.role)?;
+    mc.revoke_user_tokens(user.username).await?;
+    info!("force logout finished");
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn user_sessions(
+    Require(ext, _): Require<SessionManage>,
+    State(mc): State<Mc>,
+    Path(username): Path<String>,
+) -> Result<Json<Vec<Session>>> {
+    let user = mc.get_user(username).await?;
+    require_outranks(&ext, &user.role)?;
+    Ok(Json(mc.sessions(user.id, None).await?))
+}
+async fn revoke_user_sessions(
+    Require(ext, _): Require<SessionManage>,
+    State(mc): State<Mc>,
+    Path(username): Path<String>,
+) -> Result<StatusCode> {
+    let user = mc.get_user(username).await?;
+    require_outranks(&ext, &user.role)?;
+    let count = mc.revoke_sessions(user.id, None).await?;
+    info!("{count} sessions of {} revoked", user.username);
+    Ok(StatusCode::NO_CONTENT)
+}
+async fn revoke_user_session(
+    Require(ext, _): Require<SessionManage>,
+    State(mc): State<Mc>,
+    Path((username, id)): Path<(String, Uuid)>,
+) -> Result<StatusCode> {
+    let user = mc.get_user(username).await?;
+    require_outranks(&ext, &user.role)?;
+    info!("revoking session {id} of {}", user.username);
+    if mc.revoke_sessions(user.id, Some(id)).await? > 0 {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    Ok(StatusCode::NOT_FOUND)
+}
+async fn impersonate(
+    Require(ext, _): Require<Impersonate>,
+    State(mc): State<Mc>,
+    Path(username): Path<String>,
+) -> Result<Json<Value>> {
+    info!("{} starts impersonating {username}", ext.username);
+    let token = mc.impersonate(&ext, username.clone()).await?;
+    Ok(Json(serde_json::json!({
+        "access_token": token,
+        "token_type": "Bearer",
+        "expires_in": mc.config.tokens.impersonation_ttl_minutes * 60,
+        "impersonating": username,
+        "impersonator": ext.username,
+    })))
+}
+async fn impersonations(
+    _: Require<Impersonate>,
+    State(mc): State<Mc>,
+    Query(query): Query<EventQuery>,
+) -> Result<Json<Vec<ImpersonationEvent>>> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    Ok(Json(mc.impersonation_events(query.target, limit).await?))
+}
+async fn audit_log(
+    _: Require<AuditRead>,
+    State(mc): State<Mc>,
+    Query(filter): Query<AuditFilter>,
+) -> Result<Json<Vec<AuditEntry>>> {
+    Ok(Json(mc.audit_entries(filter).await?))
+}
+pub async fn jwks(State(mc): State<Mc>) -> Json<Value> {
+    Json(mc.keys.jwks())
+}
+async fn rotate_keys(_: Require<SigningKeyManage>, State(mc): State<Mc>) -> Result<Json<Value>> {
+    info!("key rotation started");
+    let kid = mc.keys.rotate()?;
+    info!("signing with key {kid} from now on");
+    Ok(Json(serde_json::json!({ "kid": kid })))
+}
+async fn remove_key(
+    _: Require<SigningKeyManage>,
+    State(mc): State<Mc>,
+    Path(kid): Path<String>,
+) -> Result<StatusCode> {
+    info!("removing key {kid}");
+    mc.keys.remove(&kid)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,19 +1,23 @@
 use crate::State;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use axum::Json;
-use bcrypt::{DEFAULT_COST, hash};
+use bcrypt::{DEFAULT_COST, hash, verify};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::{prelude::FromRow, query_as};
+use validator::{Validate, ValidationError, ValidationErrors};
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "Roles", rename_all = "lowercase")]
 pub enum Role {
     Admin,
+    Moderator,
     Seller,
     Buyer,
 }
+/// The roles anyone can register with.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "Roles", rename_all = "lowercase")]
-enum UserRole {
+pub enum UserRole {
     Buyer,
     Seller,
 }
@@ -22,16 +26,101 @@ pub struct User {
     pub id: i32,
     pub email: String,
     pub username: String,
+    #[serde(skip_serializing)]
     pub password: String,
     pub role: Role,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub erased_at: Option<DateTime<Utc>>,
 }
-#[derive(Deserialize, Serialize, Clone)]
+impl User {
+    pub const fn ensure_verified(&self) -> Result<()> {
+        if self.email_verified_at.is_none() {
+            return Err(Error::EmailNotVerified);
+        }
+        Ok(())
+    }
+}
+#[derive(Deserialize, Serialize, Clone, Validate)]
 pub struct NewUser {
+    #[validate(
+        email(message = "must be a valid email address"),
+        length(max = 64, message = "must be at most 64 characters")
+    )]
     email: String,
+    #[validate(
+        length(min = 3, max = 10, message = "must be 3 to 10 characters"),
+        custom(function = "username_charset")
+    )]
     username: String,
+    #[validate(
+        length(min = 8, max = 72, message = "must be 8 to 72 characters"),
+        custom(function = "password_strength")
+    )]
     password: String,
     role: UserRole,
 }
+/// The password rules of `NewUser`, for the places that only change a password.
+pub fn check_password(password: &str) -> Result<()> {
+    let mut errors = ValidationErrors::new();
+    if !(8..=72).contains(&password.chars().count()) {
+        errors.add(
+            "password",
+            ValidationError::new("length").with_message("must be 8 to 72 characters".into()),
+        );
+    }
+    if let Err(error) = password_strength(password) {
+        errors.add("password", error);
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors.into())
+}
+fn username_charset(username: &str) -> std::result::Result<(), ValidationError> {
+    if username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Ok(());
+    }
+    Err(ValidationError::new("charset")
+        .with_message("may only contain letters, digits, '_', '-' and '.'".into()))
+}
+/// At least one letter and one digit, bcrypt takes care of the rest.
+pub fn password_strength(password: &str) -> std::result::Result<(), ValidationError> {
+    if password.chars().any(char::is_alphabetic) && password.chars().any(|c| c.is_ascii_digit()) {
+        return Ok(());
+    }
+    Err(ValidationError::new("weak_password")
+        .with_message("must contain at least a letter and a digit".into()))
+}
+impl NewUser {
+    /// A registration checked against the same rules as the HTTP API.
+    pub fn new(email: String, username: String, password: String, role: UserRole) -> Result<Self> {
+        let data = Self {
+            email,
+            username,
+            password,
+            role,
+        };
+        data.validate()?;
+        Ok(data)
+    }
+    /// A registration named after `prefix` with a random suffix, so what a failed run
+    /// leaves behind never collides with the next one.
+    #[cfg(test)]
+    pub fn test(prefix: &str) -> Self {
+        let prefix: String = prefix.chars().take(5).collect();
+        let username = format!("{prefix}{}", &crate::user::refresh::random_token()[..5]);
+        Self {
+            email: format!("{username}@test.dev"),
+            username,
+            password: "azerty".to_string(),
+            role: UserRole::Seller,
+        }
+    }
+}
 impl State {
     pub async fn create_user(&self, data: Json<NewUser>) -> Result<User> {
         let password = hash(data.password.clone(), DEFAULT_COST).unwrap();
@@ -39,7 +128,7 @@ impl State {
             r#"
             INSERT INTO "User" (email, username, password, role)
             VALUES ($1, $2, $3, $4)
-            RETURNING id, email, username, password, role
+            RETURNING id, email, username, password, role, email_verified_at, deleted_at, erased_at
             "#,
         )
         .bind(&data.email)
@@ -50,29 +139,66 @@ impl State {
         .await?;
         Ok(quer)
     }
+    /// Registration stops at `UserRole`, admins come from the admin CLI with a verified email.
+    pub async fn create_admin(
+        &self,
+        email: String,
+        username: String,
+        password: String,
+    ) -> Result<User> {
+        let data = NewUser::new(email, username, password, UserRole::Buyer)?;
+        Ok(query_as::<_, User>(
+            r#"
+            INSERT INTO "User" (email, username, password, role, email_verified_at)
+            VALUES ($1, $2, $3, $4, now())
+            RETURNING id, email, username, password, role, email_verified_at, deleted_at, erased_at
+            "#,
+        )
+        .bind(data.email)
+        .bind(data.username)
+        .bind(hash(data.password, DEFAULT_COST)?)
+        .bind(Role::Admin)
+        .fetch_one(&self.pg)
+        .await?)
+    }
+    /// A rename or a new password signs the account out everywhere; the role is kept.
     pub async fn update_user(&self, data: Json<NewUser>, username: String) -> Result<User> {
+        let before = self.get_user(username.clone()).await?;
+        let password_changed = !verify(&data.password, &before.password)?;
+        let password = hash(data.password.clone(), DEFAULT_COST)?;
         let quer = query_as::<_, User>(
             r#"
-            UPDATE "User",
-            SET username = $1, email =$2, password = $3, role=$4
-            ON username = $5
+            UPDATE "User"
+            SET username = $1, email = $2, password = $3,
+                email_verified_at = CASE WHEN email = $2 THEN email_verified_at END
+            WHERE username = $4 AND deleted_at IS NULL
+            RETURNING id, email, username, password, role, email_verified_at, deleted_at, erased_at
             "#,
         )
         .bind(data.username.clone())
         .bind(data.email.clone())
-        .bind(data.password.clone())
-        .bind(data.role.clone())
+        .bind(&password)
         .bind(username)
         .fetch_one(&self.pg)
         .await?;
+        // tokens carry the username, a freed name must not keep working for whoever takes it
+        let renamed = before.username != quer.username;
+        if renamed {
+            self.revoke_user_tokens(before.username).await?;
+        }
+        if renamed || password_changed {
+            self.revoke_user_tokens(quer.username.clone()).await?;
+        }
         Ok(quer)
     }
+    /// Removes the row for good; outside of tests accounts are deactivated and purged instead.
+    #[cfg(test)]
     pub async fn delete_user(&self, username: String) -> Result<User> {
         let quer = query_as::<_, User>(
             r#"
             DELETE FROM "User"
             WHERE username = $1
-            RETURNING id, email, username, password, role
+            RETURNING id, email, username, password, role, email_verified_at, deleted_at, erased_at
             "#,
         )
         .bind(username)
@@ -84,17 +210,41 @@ impl State {
     pub async fn all_user(&self) -> Result<Vec<User>> {
         Ok(query_as::<_, User>(
             r#"
-            SELECT * FROM "User"
+            SELECT * FROM "User" WHERE deleted_at IS NULL
             "#,
         )
         .fetch_all(&self.pg)
         .await?)
     }
+    pub async fn get_user_by_id(&self, id: i32) -> Result<User> {
+        let quer = query_as::<_, User>(
+            r#"
+            SELECT * FROM "User"
+            WHERE id=$1 AND deleted_at IS NULL
+            "#,
+        )
+        .bind(id)
+        .fetch_one(&self.pg)
+        .await?;
+        Ok(quer)
+    }
+    pub async fn find_user_by_email(&self, email: String) -> Result<Option<User>> {
+        let quer = query_as::<_, User>(
+            r#"
+            SELECT * FROM "User"
+            WHERE email=$1 AND deleted_at IS NULL
+            "#,
+        )
+        .bind(email)
+        .fetch_optional(&self.pg)
+        .await?;
+        Ok(quer)
+    }
     pub async fn get_user(&self, username: String) -> Result<User> {
         let quer = query_as::<_, User>(
             r#"
             SELECT * FROM "User"
-            WHERE username=$1
+            WHERE username=$1 AND deleted_at IS NULL
             "#,
         )
         .bind(username)
@@ -106,27 +256,68 @@ impl State {
 
 #[tokio::test]
 async fn user_t() {
-    dotenvy::dotenv().ok();
-    let url = std::env::var("DATABASE_URL").unwrap();
-    let pool = sqlx::postgres::PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&url)
-        .await
-        .unwrap();
-    let sec = std::env::var("jwt_secret").unwrap();
-    let state = State {
-        pg: pool,
-        jwt_secret: sec,
-    };
+    let state = crate::test::state().await;
     println!("{:?}", state.all_user().await);
-    let data = Json(NewUser {
-        email: "amine@gmail.com".to_string(),
-        username: "aminou".to_string(),
-        password: "azerty".to_string(),
-        role: UserRole::Seller,
-    });
-    let new = state.create_user(data).await.unwrap();
+    let data = NewUser::test("amine");
+    let new = state.create_user(Json(data.clone())).await.unwrap();
     println!("{:?}", state.all_user().await);
+    let mut twin = NewUser::test("amine");
+    twin.username = new.username.clone();
+    let taken = state.create_user(Json(twin)).await.unwrap_err();
+    assert!(matches!(taken, Error::UsernameTaken));
+    let mut changed = data;
+    changed.email = format!("changed.{}", new.email);
+    let new = state
+        .update_user(Json(changed.clone()), new.username)
+        .await
+        .unwrap();
+    assert_eq!(new.email, changed.email);
+    assert!(bcrypt::verify("azerty", &new.password).unwrap());
+
+    // tokens issued under the old name die with the rename
+    let mut old = crate::user::Clains::new(new.username.clone(), Role::Seller).unwrap();
+    old.iat_ms -= 1;
+    let mut renamed = NewUser::test("amine");
+    renamed.email = new.email.clone();
+    let new = state
+        .update_user(Json(renamed), new.username)
+        .await
+        .unwrap();
+    assert!(state.revoked.is_revoked(&old));
     println!("{:?}", state.delete_user(new.username).await);
     println!("{:?}", state.all_user().await);
 }
+
+#[test]
+fn validation_t() {
+    let user = |username: &str, email: &str, password: &str| NewUser {
+        email: email.to_string(),
+        username: username.to_string(),
+        password: password.to_string(),
+        role: UserRole::Buyer,
+    };
+    assert!(
+        user("amine_27", "amine@test.dev", "azerty27")
+            .validate()
+            .is_ok()
+    );
+
+    let errors = user("amine.benali", "not-an-email", "azertyui")
+        .validate()
+        .unwrap_err();
+    let fields = errors.field_errors();
+    assert!(fields.contains_key("username"));
+    assert!(fields.contains_key("email"));
+    assert_eq!(fields["password"][0].code, "weak_password");
+
+    let errors = user("ami ne", "amine@test.dev", "azerty27")
+        .validate()
+        .unwrap_err();
+    assert_eq!(errors.field_errors()["username"][0].code, "charset");
+
+    let response = axum::response::IntoResponse::into_response(Error::from(errors));
+    assert_eq!(
+        response.status(),
+        axum::http::StatusCode::UNPROCESSABLE_ENTITY
+    );
+}
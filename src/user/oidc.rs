@@ -0,0 +1,478 @@
+use crate::State;
+use crate::config::OidcConfig;
+use crate::error::{Error, Result};
+use crate::user::model::{Role, User};
+use crate::user::refresh::{hash_token, random_token};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use bcrypt::{DEFAULT_COST, hash};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::warn;
+
+const LOGIN_TTL_MINUTES: i64 = 10;
+/// Cookie holding the nonce `begin_oidc` ties the login state to.
+pub const NONCE_COOKIE: &str = "oidc_nonce";
+
+#[derive(Clone, Default)]
+pub struct Providers {
+    http: reqwest::Client,
+    providers: Arc<BTreeMap<String, OidcConfig>>,
+}
+impl Providers {
+    pub fn new(providers: BTreeMap<String, OidcConfig>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            providers: Arc::new(providers),
+        }
+    }
+    fn get(&self, name: &str) -> Result<&OidcConfig> {
+        self.providers.get(name).ok_or(Error::UnknownProvider)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_login_at: Option<DateTime<Utc>>,
+}
+/// What the provider told us about the signed-in account.
+struct External {
+    subject: String,
+    email: Option<String>,
+    email_verified: bool,
+    username: Option<String>,
+}
+pub enum Outcome {
+    Login(User),
+    Linked(Identity),
+}
+
+/// `Set-Cookie` value that hands `nonce` to the browser starting a login, `None` clears it.
+pub fn nonce_cookie(nonce: Option<&str>) -> String {
+    let max_age = if nonce.is_some() {
+        LOGIN_TTL_MINUTES * 60
+    } else {
+        0
+    };
+    format!(
+        "{NONCE_COOKIE}={}; Path=/auth/oidc; Max-Age={max_age}; HttpOnly; Secure; SameSite=Lax",
+        nonce.unwrap_or_default()
+    )
+}
+
+/// Reads the nonce cookie out of a `Cookie` header.
+pub fn cookie_nonce(header: &str) -> Option<&str> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == NONCE_COOKIE)
+        .map(|(_, value)| value)
+}
+
+fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Reads `sub` (OIDC) or `id` (GitHub style) and the usual profile fields.
+fn external(info: &Value) -> Result<External> {
+    let subject = match info.get("sub").or_else(|| info.get("id")) {
+        Some(Value::String(sub)) => sub.clone(),
+        Some(Value::Number(id)) => id.to_string(),
+        _ => return Err(Error::IdentityProvider),
+    };
+    let text = |key: &str| info.get(key).and_then(Value::as_str).map(str::to_string);
+    Ok(External {
+        subject,
+        email: text("email"),
+        email_verified: info
+            .get("email_verified")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+        username: text("preferred_username").or_else(|| text("login")),
+    })
+}
+
+/// Usernames are at most 10 characters of `[a-z0-9_]`.
+fn username_candidate(hint: &str, attempt: usize) -> String {
+    let mut name: String = hint
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .map(|c| c.to_ascii_lowercase())
+        .take(10)
+        .collect();
+    if attempt > 0 || name.len() < 3 {
+        name.truncate(5);
+        name.push_str(&random_token()[..10 - name.len()]);
+    }
+    name
+}
+
+impl State {
+    /// Starts an authorization-code + PKCE flow and returns the URL to send the browser to,
+    /// along with the nonce the browser has to present again on the callback.
+    pub async fn begin_oidc(
+        &self,
+        provider: &str,
+        link_user_id: Option<i32>,
+    ) -> Result<(String, String)> {
+        let config = self.oidc.get(provider)?;
+        let state = random_token();
+        let nonce = random_token();
+        let verifier = random_token();
+        query!(
+            "INSERT INTO oidc_login_state
+            (state_hash, nonce_hash, provider, code_verifier, link_user_id, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+            hash_token(&state),
+            hash_token(&nonce),
+            provider,
+            verifier,
+            link_user_id,
+            Utc::now() + Duration::minutes(LOGIN_TTL_MINUTES),
+        )
+        .execute(&self.pg)
+        .await?;
+        let url = Url::parse_with_params(
+            &config.authorize_url,
+            [
+                ("response_type", "code"),
+                ("client_id", config.client_id.as_str()),
+                ("redirect_uri", config.redirect_uri.as_str()),
+                ("scope", config.scopes.as_str()),
+                ("state", state.as_str()),
+                ("code_challenge", code_challenge(&verifier).as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|_| Error::IdentityProvider)?;
+        Ok((url.into(), nonce))
+    }
+    async fn fetch_external(
+        &self,
+        config: &OidcConfig,
+        code: &str,
+        verifier: &str,
+    ) -> Result<External> {
+        let token: Value = self
+            .oidc
+            .http
+            .post(&config.token_url)
+            .header("Accept", "application/json")
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", config.redirect_uri.as_str()),
+                ("client_id", config.client_id.as_str()),
+                ("client_secret", config.client_secret.as_str()),
+                ("code_verifier", verifier),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let access_token = token
+            .get("access_token")
+            .and_then(Value::as_str)
+            .ok_or(Error::IdentityProvider)?;
+        let info: Value = self
+            .oidc
+            .http
+            .get(&config.userinfo_url)
+            .header("Accept", "application/json")
+            .header("User-Agent", "DevMarket")
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        external(&info)
+    }
+    /// Consumes the login state, then either links the identity or resolves it to a user.
+    /// `nonce` has to come from the browser that started the flow.
+    pub async fn finish_oidc(
+        &self,
+        provider: &str,
+        code: &str,
+        state: &str,
+        nonce: &str,
+    ) -> Result<Outcome> {
+        let config = self.oidc.get(provider)?;
+        let pending = query!(
+            "DELETE FROM oidc_login_state
+            WHERE state_hash = $1 AND provider = $2 AND nonce_hash = $3 AND expires_at > now()
+            RETURNING code_verifier, link_user_id",
+            hash_token(state),
+            provider,
+            hash_token(nonce),
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        .ok_or(Error::InvalidToken)?;
+        let external = self
+            .fetch_external(config, code, &pending.code_verifier)
+            .await?;
+
+        if let Some(user_id) = pending.link_user_id {
+            return Ok(Outcome::Linked(
+                self.link_identity(user_id, provider, &external).await?,
+            ));
+        }
+        let known = query!(
+            "UPDATE user_identity SET last_login_at = now()
+            WHERE provider = $1 AND subject = $2
+            RETURNING user_id",
+            provider,
+            external.subject,
+        )
+        .fetch_optional(&self.pg)
+        .await?;
+        if let Some(known) = known {
+            return Ok(Outcome::Login(self.get_user_by_id(known.user_id).await?));
+        }
+        let user = self.create_external_user(&external).await?;
+        self.link_identity(user.id, provider, &external).await?;
+        Ok(Outcome::Login(user))
+    }
+    /// New accounts never take over an existing email, that needs an explicit link.
+    async fn create_external_user(&self, external: &External) -> Result<User> {
+        let email = external.email.clone().ok_or(Error::IdentityProvider)?;
+        if self.find_user_by_email(email.clone()).await?.is_some() {
+            return Err(Error::IdentityConflict);
+        }
+        let hint = external
+            .username
+            .as_deref()
+            .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+        // nobody knows this password, a reset mail gives the user one
+        let password = hash(random_token(), DEFAULT_COST)?;
+        for attempt in 0..5 {
+            let user = query_as::<_, User>(
+                r#"
+                INSERT INTO "User" (email, username, password, role, email_verified_at)
+                VALUES ($1, $2, $3, $4, CASE WHEN $5 THEN now() END)
+                ON CONFLICT (username) DO NOTHING
+                RETURNING id, email, username, password, role, email_verified_at, deleted_at, erased_at
+                "#,
+            )
+            .bind(&email)
+            .bind(username_candidate(hint, attempt))
+            .bind(&password)
+            .bind(Role::Buyer)
+            .bind(external.email_verified)
+            .fetch_optional(&self.pg)
+            .await?;
+            if let Some(user) = user {
+                if let Err(e) = self.send_verification_email(&user).await {
+                    warn!("failed to send verification email: {e}");
+                }
+                return Ok(user);
+            }
+        }
+        Err(Error::IdentityConflict)
+    }
+    async fn link_identity(
+        &self,
+        user_id: i32,
+        provider: &str,
+        external: &External,
+    ) -> Result<Identity> {
+        query_as!(
+            Identity,
+            "INSERT INTO user_identity (user_id, provider, subject, email)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING
+            RETURNING provider, subject, email, created_at, last_login_at",
+            user_id,
+            provider,
+            external.subject,
+            external.email,
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        .ok_or(Error::IdentityConflict)
+    }
+    pub async fn identities(&self, user_id: i32) -> Result<Vec<Identity>> {
+        Ok(query_as!(
+            Identity,
+            "SELECT provider, subject, email, created_at, last_login_at
+            FROM user_identity WHERE user_id = $1
+            ORDER BY created_at",
+            user_id,
+        )
+        .fetch_all(&self.pg)
+        .await?)
+    }
+    pub async fn unlink_identity(&self, user_id: i32, provider: &str) -> Result<bool> {
+        let removed = query!(
+            "DELETE FROM user_identity WHERE user_id = $1 AND provider = $2",
+            user_id,
+            provider,
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(removed.rows_affected() > 0)
+    }
+}
+
+/// Serves `/token` and `/userinfo` like a provider would; the issued access token
+/// is the authorization code, and the code becomes the subject.
+#[cfg(test)]
+async fn mock_idp() -> OidcConfig {
+    use axum::{
+        Form, Json, Router,
+        http::{HeaderMap, StatusCode},
+        routing::{get, post},
+    };
+    async fn token(
+        Form(form): Form<BTreeMap<String, String>>,
+    ) -> std::result::Result<Json<Value>, StatusCode> {
+        let verifier = form.get("code_verifier").ok_or(StatusCode::BAD_REQUEST)?;
+        let code = form.get("code").ok_or(StatusCode::BAD_REQUEST)?;
+        // the test encodes the expected challenge as "<subject>.<challenge>"
+        let (subject, challenge) = code.split_once('.').ok_or(StatusCode::BAD_REQUEST)?;
+        if code_challenge(verifier) != challenge {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Ok(Json(serde_json::json!({ "access_token": subject })))
+    }
+    async fn userinfo(headers: HeaderMap) -> std::result::Result<Json<Value>, StatusCode> {
+        let subject = headers
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        Ok(Json(serde_json::json!({
+            "sub": subject,
+            "email": format!("{subject}@idp.test"),
+            "email_verified": true,
+            "preferred_username": subject,
+        })))
+    }
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let router = Router::new()
+        .route("/token", post(token))
+        .route("/userinfo", get(userinfo));
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    OidcConfig {
+        authorize_url: format!("{base}/authorize"),
+        token_url: format!("{base}/token"),
+        userinfo_url: format!("{base}/userinfo"),
+        client_id: "devmarket".to_string(),
+        client_secret: "secret".to_string(),
+        redirect_uri: "http://localhost/auth/oidc/mock/callback".to_string(),
+        scopes: "openid email".to_string(),
+    }
+}
+
+#[tokio::test]
+async fn oidc_t() {
+    use crate::user::model::NewUser;
+    let mut state = crate::test::state().await;
+    state.oidc = Providers::new(BTreeMap::from([("mock".to_string(), mock_idp().await)]));
+    // what the provider would append to the redirect after the user agreed,
+    // along with the nonce cookie the browser sends back
+    let authorize = |(url, nonce): (String, String), subject: &str| {
+        let url = Url::parse(&url).unwrap();
+        let param = |key: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.into_owned())
+                .unwrap()
+        };
+        (
+            format!("{subject}.{}", param("code_challenge")),
+            param("state"),
+            nonce,
+        )
+    };
+    assert!(state.begin_oidc("nope", None).await.is_err());
+    // the IdP subjects become usernames, fresh ones keep reruns apart
+    let fresh = format!("oidc{}", &random_token()[..6]);
+    let linked = format!("sub{}", &random_token()[..6]);
+
+    let (code, login, nonce) = authorize(state.begin_oidc("mock", None).await.unwrap(), &fresh);
+    let (wrong, _, _) = authorize(state.begin_oidc("mock", None).await.unwrap(), &fresh);
+    // a callback from a browser that didn't start the flow is refused
+    let (_, _, stranger) = authorize(state.begin_oidc("mock", None).await.unwrap(), &fresh);
+    assert!(
+        state
+            .finish_oidc("mock", &code, &login, &stranger)
+            .await
+            .is_err()
+    );
+    let Outcome::Login(user) = state
+        .finish_oidc("mock", &code, &login, &nonce)
+        .await
+        .unwrap()
+    else {
+        panic!("expected a login");
+    };
+    assert_eq!(&user.username, &fresh);
+    assert!(user.ensure_verified().is_ok());
+    // the state is single use
+    assert!(
+        state
+            .finish_oidc("mock", &code, &login, &nonce)
+            .await
+            .is_err()
+    );
+
+    // a verifier that doesn't match the challenge is rejected by the provider
+    let (_, other, nonce) = authorize(state.begin_oidc("mock", None).await.unwrap(), &fresh);
+    assert!(
+        state
+            .finish_oidc("mock", &wrong, &other, &nonce)
+            .await
+            .is_err()
+    );
+
+    let (code, login, nonce) = authorize(state.begin_oidc("mock", None).await.unwrap(), &fresh);
+    let Outcome::Login(again) = state
+        .finish_oidc("mock", &code, &login, &nonce)
+        .await
+        .unwrap()
+    else {
+        panic!("expected a login");
+    };
+    assert_eq!(again.id, user.id);
+
+    let local = state
+        .create_user(axum::Json(NewUser::test("oidclink")))
+        .await
+        .unwrap();
+    let (code, link, nonce) = authorize(
+        state.begin_oidc("mock", Some(local.id)).await.unwrap(),
+        &linked,
+    );
+    let Outcome::Linked(identity) = state
+        .finish_oidc("mock", &code, &link, &nonce)
+        .await
+        .unwrap()
+    else {
+        panic!("expected a link");
+    };
+    assert_eq!(&identity.subject, &linked);
+    assert_eq!(state.identities(local.id).await.unwrap().len(), 1);
+    assert!(state.unlink_identity(local.id, "mock").await.unwrap());
+    assert!(state.identities(local.id).await.unwrap().is_empty());
+
+    let cookie = nonce_cookie(Some("abc"));
+    assert!(cookie.contains("HttpOnly"));
+    assert_eq!(cookie_nonce("theme=dark; oidc_nonce=abc"), Some("abc"));
+    assert_eq!(cookie_nonce("theme=dark"), None);
+
+    state.delete_user(user.username).await.unwrap();
+    state.delete_user(local.username).await.unwrap();
+}
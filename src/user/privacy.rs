@@ -0,0 +1,256 @@
+use crate::State;
+use crate::audit::{AuditEntry, AuditFilter};
+use crate::error::Result;
+use crate::products::artifact::Artifact;
+use crate::products::download::Download;
+use crate::products::model::Product;
+use crate::products::purchase::Purchase;
+use crate::storage::BlobStore;
+use crate::user::api_key::ApiKey;
+use crate::user::model::User;
+use crate::user::oidc::Identity;
+use crate::user::refresh::random_token;
+use crate::user::session::Session;
+use crate::user::throttle::{account_key, mfa_key};
+use bcrypt::{DEFAULT_COST, hash};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::query;
+use std::io::{Cursor, Write};
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+/// Everything stored about a user, as handed out on a data subject request.
+#[derive(Serialize)]
+pub struct Export {
+    pub exported_at: DateTime<Utc>,
+    pub profile: User,
+    /// Uploaded executables are only referenced, the ZIP archive has them as files.
+    pub products: Vec<Product>,
+    /// The per-platform builds of those products, `artifacts/<id>` in the ZIP archive.
+    pub artifacts: Vec<Artifact>,
+    pub purchases: Vec<Purchase>,
+    pub downloads: Vec<Download>,
+    pub sessions: Vec<Session>,
+    pub identities: Vec<Identity>,
+    pub api_keys: Vec<ApiKey>,
+    pub audit: Vec<AuditEntry>,
+}
+
+impl Export {
+    /// One JSON file per section plus `products/<id>.exe` and `artifacts/<id>` for the uploads.
+    pub async fn into_zip(self, blobs: &dyn BlobStore) -> Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        for product in &self.products {
+            if let Some(sha256) = &product.executable_sha256 {
+                let executable = blobs.get(sha256).await?;
+                zip.start_file(format!("products/{}.exe", product.id), options)?;
+                zip.write_all(&executable)?;
+            }
+        }
+        for artifact in &self.artifacts {
+            zip.start_file(format!("artifacts/{}", artifact.id), options)?;
+            zip.write_all(&blobs.get(&artifact.sha256).await?)?;
+        }
+        zip.set_comment(format!(
+            "DevMarket export of {}",
+            self.exported_at.to_rfc3339()
+        ));
+        if let Value::Object(sections) = serde_json::to_value(&self)? {
+            for (name, section) in sections.into_iter().filter(|(_, s)| !s.is_string()) {
+                zip.start_file(format!("{name}.json"), options)?;
+                zip.write_all(&serde_json::to_vec_pretty(&section)?)?;
+            }
+        }
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+impl State {
+    pub async fn export_user(&self, user: User) -> Result<Export> {
+        let products = self.products_of(user.id).await?;
+        let mut audit = self
+            .audit_entries(AuditFilter {
+                actor: Some(user.username.clone()),
+                limit: Some(1000),
+                ..AuditFilter::default()
+            })
+            .await?;
+        audit.extend(
+            self.audit_entries(AuditFilter {
+                target_type: Some("user".to_string()),
+                target_id: Some(user.id.to_string()),
+                limit: Some(1000),
+                ..AuditFilter::default()
+            })
+            .await?
+            .into_iter()
+            .filter(|entry| entry.actor.as_ref() != Some(&user.username)),
+        );
+        audit.sort_by_key(|entry| entry.id);
+        Ok(Export {
+            exported_at: Utc::now(),
+            products,
+            artifacts: self.artifacts_of_owner(user.id).await?,
+            purchases: self.purchases_of(user.id).await?,
+            downloads: self.downloads_of(user.id).await?,
+            sessions: self.sessions(user.id, None).await?,
+            identities: self.identities(user.id).await?,
+            api_keys: self.api_keys(user.id).await?,
+            audit,
+            profile: user,
+        })
+    }
+    /// Anonymizes the account instead of deleting it, so purchases and products keep
+    /// a valid user id. Credentials and linked data go, the audit log keeps its entries
+    /// under the pseudonym without addresses or the account's personal fields.
+    pub async fn erase_user(&self, user: &User) -> Result<()> {
+        let mut tx = self.pg.begin().await?;
+        query!(
+            "UPDATE Product SET unlisted_at = now() WHERE owner_id = $1 AND unlisted_at IS NULL",
+            user.id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        for table in [
+            "refresh_token",
+            "session",
+            "password_reset_token",
+            "email_verification_token",
+            "user_mfa",
+            "mfa_recovery_code",
+            "api_key",
+            "user_identity",
+        ] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE user_id = $1"))
+                .bind(user.id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        query!(
+            "DELETE FROM oidc_login_state WHERE link_user_id = $1",
+            user.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!(
+            "DELETE FROM login_throttle WHERE key = ANY($1)",
+            &[account_key(&user.username), mfa_key(&user.username)],
+        )
+        .execute(&mut *tx)
+        .await?;
+        let pseudonym = format!("~{}", user.id);
+        query!("SELECT set_config('devmarket.audit_erasure', 'on', true)")
+            .fetch_one(&mut *tx)
+            .await?;
+        query!(
+            "UPDATE audit_log SET
+                actor = CASE WHEN actor = $1 THEN $2 ELSE actor END,
+                impersonator = CASE WHEN impersonator = $1 THEN $2 ELSE impersonator END,
+                ip = CASE WHEN actor = $1 THEN NULL ELSE ip END
+            WHERE actor = $1 OR impersonator = $1",
+            user.username,
+            pseudonym,
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!(
+            "UPDATE audit_log SET diff = diff - ARRAY['email', 'username']
+            WHERE target_type = 'user' AND target_id = $1",
+            user.id.to_string(),
+        )
+        .execute(&mut *tx)
+        .await?;
+        // nobody can log in with a random password nobody has seen
+        query!(
+            r#"UPDATE "User"
+            SET email = $2, username = $3, password = $4, email_verified_at = NULL,
+                deleted_at = COALESCE(deleted_at, now()), erased_at = now()
+            WHERE id = $1"#,
+            user.id,
+            format!("erased-{}@invalid", user.id),
+            pseudonym,
+            hash(random_token(), DEFAULT_COST)?,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.revoke_user_tokens(user.username.clone()).await
+    }
+}
+
+#[tokio::test]
+async fn privacy_t() {
+    use crate::products::model::NewProduct;
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let user = state
+        .create_user(axum::Json(NewUser::test("privacy")))
+        .await
+        .unwrap();
+    let product = state
+        .new_product(axum::Json(NewProduct {
+            name: "exported".to_string(),
+            description: "ships in the archive".to_string(),
+            price: 3,
+            owner_id: user.id,
+            executable: Some(crate::products::format::test_executable(&[1])),
+        }))
+        .await
+        .unwrap();
+    let build = crate::products::format::test_executable(b"arm64 build");
+    let body = futures_util::stream::iter([Ok(axum::body::Bytes::from(build))]);
+    let artifact = state
+        .upload_artifact(product.id, body, &[], None)
+        .await
+        .unwrap();
+
+    let export = state.export_user(user.clone()).await.unwrap();
+    let json = serde_json::to_value(&export).unwrap();
+    assert!(json["profile"]["password"].is_null());
+    assert_eq!(json["products"][0]["name"], "exported");
+    assert_eq!(json["products"][0]["executable_size"], 91);
+
+    let archive = export.into_zip(&*state.blobs).await.unwrap();
+    let mut zip = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+    assert!(zip.by_name("profile.json").is_ok());
+    assert!(zip.by_name(&format!("products/{}.exe", product.id)).is_ok());
+    assert!(zip.by_name(&format!("artifacts/{}", artifact.id)).is_ok());
+
+    let audit = crate::audit::Audit {
+        ip: Some("203.0.113.9".to_string()),
+        ..crate::audit::Audit::default()
+    }
+    .by_user(&user);
+    state
+        .audit(&audit, "user.create", "user", user.id, None, Some(&user))
+        .await;
+
+    state.erase_user(&user).await.unwrap();
+    let entries = state
+        .audit_entries(AuditFilter {
+            actor: Some(format!("~{}", user.id)),
+            ..AuditFilter::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].ip.is_none());
+    assert!(entries[0].diff.get("email").is_none());
+    assert!(entries[0].diff.get("id").is_some());
+    assert!(state.get_user(user.username.clone()).await.is_err());
+    let erased = sqlx::query_as::<_, User>(r#"SELECT * FROM "User" WHERE id = $1"#)
+        .bind(user.id)
+        .fetch_one(&state.pg)
+        .await
+        .unwrap();
+    assert_eq!(erased.username, format!("~{}", user.id));
+    assert!(erased.erased_at.is_some());
+    let kept = state.get_product(product.id).await.unwrap();
+    assert_eq!(kept.owner_id, Some(user.id));
+    assert!(kept.unlisted_at.is_some());
+
+    state.delete_product(product.id).await.unwrap();
+    state.delete_user(erased.username).await.unwrap();
+}
@@ -0,0 +1,164 @@
+use crate::State;
+use crate::error::{Error, Result};
+use chrono::{DateTime, Duration, Utc};
+use rand::{RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as};
+use tracing::warn;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct RefreshToken {
+    pub id: i64,
+    pub user_id: i32,
+    pub family_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Only the SHA-256 of a refresh token is stored, the raw value is handed to the client once.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+impl State {
+    pub async fn issue_refresh_token(&self, user_id: i32, family_id: Uuid) -> Result<String> {
+        let token = random_token();
+        let expires_at = Utc::now() + Duration::days(self.config.tokens.refresh_ttl_days);
+        query!(
+            "INSERT INTO refresh_token (user_id, family_id, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4)",
+            user_id,
+            family_id,
+            hash_token(&token),
+            expires_at,
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(token)
+    }
+    /// Consumes `token` and returns the owner together with its replacement.
+    /// Presenting a token that was already used or revoked revokes its whole family.
+    pub async fn rotate_refresh_token(&self, token: &str) -> Result<(i32, Uuid, String)> {
+        let mut tx = self.pg.begin().await?;
+        let stored = query_as!(
+            RefreshToken,
+            "SELECT id, user_id, family_id, expires_at, used_at, revoked_at FROM refresh_token
+            WHERE token_hash = $1
+            FOR UPDATE",
+            hash_token(token),
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(Error::InvalidToken)?;
+
+        if stored.used_at.is_some() || stored.revoked_at.is_some() {
+            query!(
+                "UPDATE refresh_token SET revoked_at = now()
+                WHERE family_id = $1 AND revoked_at IS NULL",
+                stored.family_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+            query!(
+                "UPDATE session SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+                stored.family_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            self.revoked.revoke_sessions(&[stored.family_id]);
+            warn!(
+                "refresh token reuse detected, family {} revoked",
+                stored.family_id
+            );
+            return Err(Error::InvalidToken);
+        }
+        if stored.expires_at < Utc::now() {
+            return Err(Error::InvalidToken);
+        }
+
+        query!(
+            "UPDATE refresh_token SET used_at = now() WHERE id = $1",
+            stored.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let next = random_token();
+        query!(
+            "INSERT INTO refresh_token (user_id, family_id, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4)",
+            stored.user_id,
+            stored.family_id,
+            hash_token(&next),
+            Utc::now() + Duration::days(self.config.tokens.refresh_ttl_days),
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok((stored.user_id, stored.family_id, next))
+    }
+    /// Revokes every token of the family `token` belongs to, ends its session and returns the family id.
+    pub async fn revoke_refresh_family(&self, token: &str) -> Result<Uuid> {
+        let family = query!(
+            "SELECT family_id FROM refresh_token WHERE token_hash = $1",
+            hash_token(token),
+        )
+        .fetch_optional(&self.pg)
+        .await?
+        .ok_or(Error::InvalidToken)?
+        .family_id;
+        query!(
+            "UPDATE refresh_token SET revoked_at = now()
+            WHERE family_id = $1 AND revoked_at IS NULL",
+            family,
+        )
+        .execute(&self.pg)
+        .await?;
+        query!(
+            "UPDATE session SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+            family,
+        )
+        .execute(&self.pg)
+        .await?;
+        self.revoked.revoke_sessions(&[family]);
+        Ok(family)
+    }
+}
+
+#[tokio::test]
+async fn refresh_t() {
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let user = state
+        .create_user(axum::Json(NewUser::test("refresh")))
+        .await
+        .unwrap();
+
+    let first = state
+        .issue_refresh_token(user.id, Uuid::new_v4())
+        .await
+        .unwrap();
+    let (owner, family, second) = state.rotate_refresh_token(&first).await.unwrap();
+    assert_eq!(owner, user.id);
+
+    // the first token was already rotated, replaying it must kill the family
+    assert!(state.rotate_refresh_token(&first).await.is_err());
+    assert!(state.rotate_refresh_token(&second).await.is_err());
+    let mut claims = crate::user::Clains::new(user.username.clone(), user.role).unwrap();
+    claims.sid = Some(family);
+    assert!(state.revoked.is_revoked(&claims));
+
+    let third = state.issue_refresh_token(user.id, family).await.unwrap();
+    assert_eq!(state.revoke_refresh_family(&third).await.unwrap(), family);
+    assert!(state.rotate_refresh_token(&third).await.is_err());
+
+    state.delete_user(user.username).await.unwrap();
+}
@@ -0,0 +1,119 @@
+use crate::State;
+use crate::error::{Error, Result};
+use crate::mail::Mail;
+use crate::user::model::check_password;
+use crate::user::refresh::{hash_token, random_token};
+use bcrypt::{DEFAULT_COST, hash};
+use chrono::{Duration, Utc};
+use sqlx::query;
+use tracing::info;
+
+impl State {
+    /// Replaces any pending reset token of the user with a new one.
+    pub async fn create_reset_token(&self, user_id: i32) -> Result<String> {
+        let token = random_token();
+        let mut tx = self.pg.begin().await?;
+        query!(
+            "DELETE FROM password_reset_token WHERE user_id = $1 AND used_at IS NULL",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!(
+            "INSERT INTO password_reset_token (user_id, token_hash, expires_at)
+            VALUES ($1, $2, $3)",
+            user_id,
+            hash_token(&token),
+            Utc::now() + Duration::minutes(self.config.tokens.reset_ttl_minutes),
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+    /// Mails a reset token when `email` belongs to an account and silently does
+    /// nothing otherwise, so the endpoint can't be used to probe for accounts.
+    pub async fn request_password_reset(&self, email: String) -> Result<()> {
+        let Some(user) = self.find_user_by_email(email).await? else {
+            info!("password reset requested for an unknown email");
+            return Ok(());
+        };
+        let token = self.create_reset_token(user.id).await?;
+        self.mailer
+            .send(Mail {
+                to: user.email,
+                subject: "Reset your DevMarket password".to_string(),
+                body: format!(
+                    "Hello {},\n\nuse the following token to choose a new password:\n\n{token}\n\n\
+                    It expires in {} minutes. If you didn't ask for it you can ignore this mail.",
+                    user.username, self.config.tokens.reset_ttl_minutes
+                ),
+            })
+            .await
+    }
+    /// Consumes `token`, sets the new password and signs the user out everywhere.
+    pub async fn reset_password(&self, token: &str, password: &str) -> Result<()> {
+        check_password(password)?;
+        let mut tx = self.pg.begin().await?;
+        let user_id = query!(
+            "UPDATE password_reset_token SET used_at = now()
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+            RETURNING user_id",
+            hash_token(token),
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(Error::InvalidToken)?
+        .user_id;
+        let username = query!(
+            r#"UPDATE "User" SET password = $1 WHERE id = $2 RETURNING username"#,
+            hash(password, DEFAULT_COST)?,
+            user_id,
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .username;
+        tx.commit().await?;
+        self.revoke_user_tokens(username).await
+    }
+    /// Sets a new password without a token, for the admin CLI, and signs the user out.
+    pub async fn set_password(&self, username: String, password: &str) -> Result<()> {
+        check_password(password)?;
+        query!(
+            r#"UPDATE "User" SET password = $1 WHERE username = $2 AND deleted_at IS NULL RETURNING id"#,
+            hash(password, DEFAULT_COST)?,
+            username,
+        )
+        .fetch_one(&self.pg)
+        .await?;
+        self.revoke_user_tokens(username).await
+    }
+}
+
+#[tokio::test]
+async fn reset_t() {
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let user = state
+        .create_user(axum::Json(NewUser::test("reset")))
+        .await
+        .unwrap();
+    let refresh = state
+        .issue_refresh_token(user.id, uuid::Uuid::new_v4())
+        .await
+        .unwrap();
+
+    let replaced = state.create_reset_token(user.id).await.unwrap();
+    let token = state.create_reset_token(user.id).await.unwrap();
+    assert!(state.reset_password(&replaced, "qwerty12").await.is_err());
+    // a weak password leaves the token usable
+    assert!(state.reset_password(&token, "").await.is_err());
+    state.reset_password(&token, "qwerty12").await.unwrap();
+    assert!(state.reset_password(&token, "again123").await.is_err());
+
+    let updated = state.get_user(user.username.clone()).await.unwrap();
+    assert!(bcrypt::verify("qwerty12", &updated.password).unwrap());
+    assert!(state.rotate_refresh_token(&refresh).await.is_err());
+
+    state.delete_user(user.username).await.unwrap();
+}
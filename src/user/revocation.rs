@@ -0,0 +1,203 @@
+use crate::State;
+use crate::error::Result;
+use crate::user::Clains;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgPool, query, query_scalar};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use tracing::info;
+use uuid::Uuid;
+
+/// In-memory copy of the revocation tables so `IsAuth` never hits the database.
+#[derive(Clone, Default)]
+pub struct Revocations {
+    inner: Arc<RwLock<Cache>>,
+    /// Lifetime of access tokens, older revocations can't match anything.
+    window: Duration,
+}
+#[derive(Default)]
+struct Cache {
+    tokens: HashSet<Uuid>,
+    users: HashMap<String, i64>,
+    sessions: HashSet<Uuid>,
+}
+
+impl Revocations {
+    pub async fn load(pg: &PgPool, access_ttl_minutes: i64) -> Result<Self> {
+        let revocations = Self {
+            window: Duration::minutes(access_ttl_minutes),
+            ..Self::default()
+        };
+        revocations.reload(pg).await?;
+        Ok(revocations)
+    }
+    async fn reload(&self, pg: &PgPool) -> Result<()> {
+        let tokens = query!("SELECT jti FROM revoked_token WHERE expires_at > now()")
+            .fetch_all(pg)
+            .await?
+            .into_iter()
+            .map(|row| row.jti)
+            .collect();
+        let users = query!("SELECT username, revoked_before FROM user_revocation")
+            .fetch_all(pg)
+            .await?
+            .into_iter()
+            .map(|row| (row.username, row.revoked_before.timestamp_millis()))
+            .collect();
+        let sessions = query!(
+            "SELECT id FROM session WHERE revoked_at > $1",
+            Utc::now() - self.window,
+        )
+        .fetch_all(pg)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect();
+        *self.inner.write().expect("revocation cache poisoned") = Cache {
+            tokens,
+            users,
+            sessions,
+        };
+        Ok(())
+    }
+    /// User revocations compare milliseconds, so the session started right after a password
+    /// reset stays valid while tokens from the same second before it don't.
+    pub fn is_revoked(&self, claims: &Clains) -> bool {
+        let cache = self.inner.read().expect("revocation cache poisoned");
+        cache.tokens.contains(&claims.jti)
+            || claims.sid.is_some_and(|sid| cache.sessions.contains(&sid))
+            || cache
+                .users
+                .get(&claims.username)
+                .is_some_and(|before| issued_at_ms(claims) < *before)
+    }
+    pub fn revoke_sessions(&self, ids: &[Uuid]) {
+        self.inner
+            .write()
+            .expect("revocation cache poisoned")
+            .sessions
+            .extend(ids);
+    }
+}
+
+/// Tokens minted before `iat_ms` existed only know the second they were issued in.
+fn issued_at_ms(claims: &Clains) -> i64 {
+    if claims.iat_ms > 0 {
+        return claims.iat_ms;
+    }
+    i64::try_from(claims.iat)
+        .unwrap_or(i64::MAX)
+        .saturating_mul(1000)
+}
+
+impl State {
+    pub async fn revoke_token(&self, claims: &Clains) -> Result<()> {
+        let expires_at =
+            DateTime::from_timestamp(i64::try_from(claims.exp)?, 0).unwrap_or_else(Utc::now);
+        query!(
+            "INSERT INTO revoked_token (jti, expires_at) VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING",
+            claims.jti,
+            expires_at,
+        )
+        .execute(&self.pg)
+        .await?;
+        self.revoked
+            .inner
+            .write()
+            .expect("revocation cache poisoned")
+            .tokens
+            .insert(claims.jti);
+        Ok(())
+    }
+    /// Rejects every access token issued to `username` so far and revokes their refresh tokens
+    /// and API keys.
+    pub async fn revoke_user_tokens(&self, username: String) -> Result<()> {
+        let now = Utc::now();
+        query!(
+            "INSERT INTO user_revocation (username, revoked_before) VALUES ($1, $2)
+            ON CONFLICT (username) DO UPDATE SET revoked_before = EXCLUDED.revoked_before",
+            username,
+            now,
+        )
+        .execute(&self.pg)
+        .await?;
+        query!(
+            r#"UPDATE refresh_token SET revoked_at = now()
+            WHERE revoked_at IS NULL
+            AND user_id IN (SELECT id FROM "User" WHERE username = $1)"#,
+            username,
+        )
+        .execute(&self.pg)
+        .await?;
+        let sessions = query_scalar!(
+            r#"UPDATE session SET revoked_at = now()
+            WHERE revoked_at IS NULL
+            AND user_id IN (SELECT id FROM "User" WHERE username = $1)
+            RETURNING id"#,
+            username,
+        )
+        .fetch_all(&self.pg)
+        .await?;
+        self.revoked.revoke_sessions(&sessions);
+        // API keys are checked against the database, not the cutoff
+        query!(
+            r#"UPDATE api_key SET revoked_at = now()
+            WHERE revoked_at IS NULL
+            AND user_id IN (SELECT id FROM "User" WHERE username = $1)"#,
+            username,
+        )
+        .execute(&self.pg)
+        .await?;
+        self.revoked
+            .inner
+            .write()
+            .expect("revocation cache poisoned")
+            .users
+            .insert(username, now.timestamp_millis());
+        Ok(())
+    }
+    /// Drops entries that can no longer match a live token and picks up
+    /// revocations written by other instances.
+    pub async fn sync_revocations(&self) -> Result<()> {
+        query!("DELETE FROM revoked_token WHERE expires_at <= now()")
+            .execute(&self.pg)
+            .await?;
+        query!(
+            "DELETE FROM user_revocation WHERE revoked_before < $1",
+            Utc::now() - self.revoked.window,
+        )
+        .execute(&self.pg)
+        .await?;
+        self.revoked.reload(&self.pg).await?;
+        info!("revocation list synced");
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn revocation_t() {
+    let state = crate::test::state().await;
+    let claims = Clains::new("revoke".to_string(), crate::user::model::Role::Buyer).unwrap();
+    assert!(!state.revoked.is_revoked(&claims));
+    state.revoke_token(&claims).await.unwrap();
+    assert!(state.revoked.is_revoked(&claims));
+
+    let mut other = Clains::new("revoked".to_string(), crate::user::model::Role::Buyer).unwrap();
+    other.iat_ms -= 1;
+    state
+        .revoke_user_tokens(other.username.clone())
+        .await
+        .unwrap();
+    assert!(state.revoked.is_revoked(&other));
+    // a token issued right after the revocation, e.g. by the next login, is valid
+    let next = Clains::new("revoked".to_string(), crate::user::model::Role::Buyer).unwrap();
+    assert!(!state.revoked.is_revoked(&next));
+
+    // a fresh instance only sees what made it to the database
+    let reloaded = Revocations::load(&state.pg, state.config.tokens.access_ttl_minutes)
+        .await
+        .unwrap();
+    assert!(reloaded.is_revoked(&claims));
+    assert!(reloaded.is_revoked(&other));
+}
@@ -0,0 +1,133 @@
+use crate::State;
+use crate::error::Result;
+use crate::ext::Device;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{query, query_as};
+use tracing::warn;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    /// The session the request was made with.
+    pub current: bool,
+}
+
+impl State {
+    pub async fn create_session(&self, user_id: i32, device: &Device) -> Result<Uuid> {
+        let id = Uuid::new_v4();
+        query!(
+            "INSERT INTO session (id, user_id, user_agent, ip) VALUES ($1, $2, $3, $4)",
+            id,
+            user_id,
+            device.user_agent,
+            device.ip.map(|ip| ip.to_string()),
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(id)
+    }
+    /// Records activity in the background, at most once a minute per session.
+    pub fn touch_session(&self, id: Uuid) {
+        let pg = self.pg.clone();
+        tokio::spawn(async move {
+            let touched = query!(
+                "UPDATE session SET last_seen_at = now()
+                WHERE id = $1 AND last_seen_at < now() - interval '1 minute'",
+                id,
+            )
+            .execute(&pg)
+            .await;
+            if let Err(e) = touched {
+                warn!("failed to record session activity: {e}");
+            }
+        });
+    }
+    /// Live sessions of the user, `current` marks the one with id `current`.
+    pub async fn sessions(&self, user_id: i32, current: Option<Uuid>) -> Result<Vec<Session>> {
+        Ok(query_as!(
+            Session,
+            r#"SELECT id, user_agent, ip, created_at, last_seen_at, id = $2 IS TRUE AS "current!"
+            FROM session
+            WHERE user_id = $1 AND revoked_at IS NULL
+            AND EXISTS (
+                SELECT 1 FROM refresh_token
+                WHERE family_id = session.id AND revoked_at IS NULL AND expires_at > now()
+            )
+            ORDER BY last_seen_at DESC"#,
+            user_id,
+            current,
+        )
+        .fetch_all(&self.pg)
+        .await?)
+    }
+    /// Ends one session (or all of them without `id`) along with its tokens.
+    pub async fn revoke_sessions(&self, user_id: i32, id: Option<Uuid>) -> Result<u64> {
+        let mut tx = self.pg.begin().await?;
+        let revoked: Vec<Uuid> = query!(
+            "UPDATE session SET revoked_at = now()
+            WHERE user_id = $1 AND revoked_at IS NULL AND ($2::UUID IS NULL OR id = $2)
+            RETURNING id",
+            user_id,
+            id,
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect();
+        query!(
+            "UPDATE refresh_token SET revoked_at = now()
+            WHERE family_id = ANY($1) AND revoked_at IS NULL",
+            &revoked,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.revoked.revoke_sessions(&revoked);
+        Ok(revoked.len() as u64)
+    }
+}
+
+#[tokio::test]
+async fn session_t() {
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let user = state
+        .create_user(axum::Json(NewUser::test("session")))
+        .await
+        .unwrap();
+    let device = Device {
+        user_agent: Some("curl/8.0".to_string()),
+        ip: Some(std::net::IpAddr::from([127, 0, 0, 1])),
+    };
+
+    let first = state.create_session(user.id, &device).await.unwrap();
+    let refresh = state.issue_refresh_token(user.id, first).await.unwrap();
+    let second = state.create_session(user.id, &device).await.unwrap();
+    state.issue_refresh_token(user.id, second).await.unwrap();
+
+    let sessions = state.sessions(user.id, Some(first)).await.unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert!(sessions.iter().any(|s| s.id == first && s.current));
+    assert_eq!(sessions[0].ip.as_deref(), Some("127.0.0.1"));
+
+    assert_eq!(
+        state.revoke_sessions(user.id, Some(first)).await.unwrap(),
+        1
+    );
+    assert!(state.rotate_refresh_token(&refresh).await.is_err());
+    let mut claims = crate::user::Clains::new(user.username.clone(), user.role.clone()).unwrap();
+    claims.sid = Some(first);
+    assert!(state.revoked.is_revoked(&claims));
+
+    assert_eq!(state.revoke_sessions(user.id, None).await.unwrap(), 1);
+    assert!(state.sessions(user.id, None).await.unwrap().is_empty());
+
+    state.delete_user(user.username).await.unwrap();
+}
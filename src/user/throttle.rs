@@ -0,0 +1,180 @@
+use crate::State;
+use crate::error::{Error, Result};
+use crate::user::refresh::hash_token;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::{query, query_as};
+
+/// Failures allowed before an account is locked.
+const ACCOUNT_FREE_FAILURES: i32 = 5;
+/// Failures allowed before a client address is locked, several users can share one.
+const IP_FREE_FAILURES: i32 = 20;
+const BASE_LOCK_SECONDS: i64 = 30;
+const MAX_LOCK_SECONDS: i64 = 60 * 60;
+/// Failures older than this are forgotten.
+const FAILURE_WINDOW_HOURS: i64 = 24;
+
+#[derive(Debug, Serialize)]
+pub struct Lockout {
+    pub key: String,
+    pub failures: i32,
+    pub last_failure_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// Longest name kept readable in a key, longer ones are hashed to fit the column.
+const MAX_KEY_NAME: usize = 64;
+
+fn throttle_key(kind: &str, name: &str) -> String {
+    if name.len() <= MAX_KEY_NAME {
+        return format!("{kind}:{name}");
+    }
+    format!("{kind}:#{}", hash_token(name))
+}
+pub fn account_key(username: &str) -> String {
+    throttle_key("user", username)
+}
+pub fn ip_key(ip: std::net::IpAddr) -> String {
+    format!("ip:{ip}")
+}
+/// Second factor attempts, counted apart from passwords.
+pub fn mfa_key(username: &str) -> String {
+    throttle_key("mfa", username)
+}
+
+/// Lock duration after `failures` failed attempts, doubling with every failure past the free ones.
+fn lock_seconds(failures: i32, free: i32) -> Option<i64> {
+    let over = failures - free;
+    if over <= 0 {
+        return None;
+    }
+    Some(
+        BASE_LOCK_SECONDS
+            .saturating_mul(1 << (over - 1).min(20))
+            .min(MAX_LOCK_SECONDS),
+    )
+}
+
+impl State {
+    pub async fn ensure_not_locked(&self, keys: &[String]) -> Result<()> {
+        let locked_until = query!(
+            "SELECT MAX(locked_until) AS locked_until FROM login_throttle
+            WHERE key = ANY($1) AND locked_until > now()",
+            keys,
+        )
+        .fetch_one(&self.pg)
+        .await?
+        .locked_until;
+        if let Some(until) = locked_until {
+            let secs = (until - Utc::now()).num_seconds().max(1);
+            return Err(Error::TooManyAttempts(secs.unsigned_abs()));
+        }
+        Ok(())
+    }
+    pub async fn record_login_failure(&self, username: &str, ip: std::net::IpAddr) -> Result<()> {
+        self.record_failure(&account_key(username), ACCOUNT_FREE_FAILURES)
+            .await?;
+        self.record_failure(&ip_key(ip), IP_FREE_FAILURES).await
+    }
+    pub async fn record_mfa_failure(&self, username: &str) -> Result<()> {
+        self.record_failure(&mfa_key(username), ACCOUNT_FREE_FAILURES)
+            .await
+    }
+    async fn record_failure(&self, key: &str, free: i32) -> Result<()> {
+        let failures = query!(
+            "INSERT INTO login_throttle (key, failures) VALUES ($1, 1)
+            ON CONFLICT (key) DO UPDATE SET
+                failures = CASE WHEN login_throttle.last_failure_at < $2 THEN 1
+                    ELSE login_throttle.failures + 1 END,
+                last_failure_at = now()
+            RETURNING failures",
+            key,
+            Utc::now() - Duration::hours(FAILURE_WINDOW_HOURS),
+        )
+        .fetch_one(&self.pg)
+        .await?
+        .failures;
+        if let Some(secs) = lock_seconds(failures, free) {
+            query!(
+                "UPDATE login_throttle SET locked_until = $2 WHERE key = $1",
+                key,
+                Utc::now() + Duration::seconds(secs),
+            )
+            .execute(&self.pg)
+            .await?;
+        }
+        Ok(())
+    }
+    pub async fn clear_login_failures(&self, key: &str) -> Result<bool> {
+        let cleared = query!("DELETE FROM login_throttle WHERE key = $1", key)
+            .execute(&self.pg)
+            .await?;
+        Ok(cleared.rows_affected() > 0)
+    }
+    /// Forgets failures that fell out of the window and aren't holding a lock.
+    pub async fn purge_login_throttle(&self) -> Result<u64> {
+        let purged = query!(
+            "DELETE FROM login_throttle
+            WHERE last_failure_at < $1 AND (locked_until IS NULL OR locked_until < now())",
+            Utc::now() - Duration::hours(FAILURE_WINDOW_HOURS),
+        )
+        .execute(&self.pg)
+        .await?;
+        Ok(purged.rows_affected())
+    }
+    pub async fn lockouts(&self) -> Result<Vec<Lockout>> {
+        Ok(query_as!(
+            Lockout,
+            "SELECT key, failures, last_failure_at, locked_until FROM login_throttle
+            WHERE locked_until > now()
+            ORDER BY locked_until DESC"
+        )
+        .fetch_all(&self.pg)
+        .await?)
+    }
+}
+
+#[test]
+fn lock_seconds_t() {
+    assert_eq!(lock_seconds(5, 5), None);
+    assert_eq!(lock_seconds(6, 5), Some(30));
+    assert_eq!(lock_seconds(7, 5), Some(60));
+    assert_eq!(lock_seconds(9, 5), Some(240));
+    assert_eq!(lock_seconds(500, 5), Some(MAX_LOCK_SECONDS));
+
+    assert_eq!(account_key("amine"), "user:amine");
+    assert!(account_key(&"a".repeat(10_000)).len() <= 128);
+}
+
+#[tokio::test]
+async fn throttle_t() {
+    let state = crate::test::state().await;
+    let ip = std::net::IpAddr::from([10, 0, 0, 7]);
+    let keys = [account_key("throttle"), ip_key(ip)];
+    for key in &keys {
+        state.clear_login_failures(key).await.unwrap();
+    }
+
+    for _ in 0..ACCOUNT_FREE_FAILURES {
+        state.ensure_not_locked(&keys).await.unwrap();
+        state.record_login_failure("throttle", ip).await.unwrap();
+    }
+    state.ensure_not_locked(&keys).await.unwrap();
+    state.record_login_failure("throttle", ip).await.unwrap();
+    assert!(matches!(
+        state.ensure_not_locked(&keys).await,
+        Err(Error::TooManyAttempts(_))
+    ));
+    assert!(
+        state
+            .lockouts()
+            .await
+            .unwrap()
+            .iter()
+            .any(|l| l.key == keys[0])
+    );
+
+    assert!(state.clear_login_failures(&keys[0]).await.unwrap());
+    state.ensure_not_locked(&keys).await.unwrap();
+    state.clear_login_failures(&keys[1]).await.unwrap();
+}
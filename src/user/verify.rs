@@ -0,0 +1,90 @@
+use crate::State;
+use crate::error::{Error, Result};
+use crate::mail::Mail;
+use crate::user::model::User;
+use crate::user::refresh::{hash_token, random_token};
+use chrono::{Duration, Utc};
+use sqlx::query;
+
+impl State {
+    /// Replaces any pending verification token of the user with a new one.
+    pub async fn create_verification_token(&self, user_id: i32) -> Result<String> {
+        let token = random_token();
+        let mut tx = self.pg.begin().await?;
+        query!(
+            "DELETE FROM email_verification_token WHERE user_id = $1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        query!(
+            "INSERT INTO email_verification_token (user_id, token_hash, expires_at)
+            VALUES ($1, $2, $3)",
+            user_id,
+            hash_token(&token),
+            Utc::now() + Duration::hours(self.config.tokens.verify_ttl_hours),
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+    pub async fn send_verification_email(&self, user: &User) -> Result<()> {
+        if user.email_verified_at.is_some() {
+            return Ok(());
+        }
+        let token = self.create_verification_token(user.id).await?;
+        self.mailer
+            .send(Mail {
+                to: user.email.clone(),
+                subject: "Confirm your DevMarket email address".to_string(),
+                body: format!(
+                    "Hello {},\n\nuse the following token to confirm your email address:\n\n{token}\n\n\
+                    It expires in {} hours.",
+                    user.username, self.config.tokens.verify_ttl_hours
+                ),
+            })
+            .await
+    }
+    pub async fn verify_email(&self, token: &str) -> Result<()> {
+        let mut tx = self.pg.begin().await?;
+        let user_id = query!(
+            "DELETE FROM email_verification_token
+            WHERE token_hash = $1 AND expires_at > now()
+            RETURNING user_id",
+            hash_token(token),
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(Error::InvalidToken)?
+        .user_id;
+        query!(
+            r#"UPDATE "User" SET email_verified_at = now()
+            WHERE id = $1 AND email_verified_at IS NULL"#,
+            user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn verify_t() {
+    use crate::user::model::NewUser;
+    let state = crate::test::state().await;
+    let user = state
+        .create_user(axum::Json(NewUser::test("verify")))
+        .await
+        .unwrap();
+    assert!(user.ensure_verified().is_err());
+
+    let token = state.create_verification_token(user.id).await.unwrap();
+    state.verify_email(&token).await.unwrap();
+    assert!(state.verify_email(&token).await.is_err());
+    let user = state.get_user(user.username).await.unwrap();
+    assert!(user.ensure_verified().is_ok());
+
+    state.delete_user(user.username).await.unwrap();
+}